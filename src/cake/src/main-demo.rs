@@ -3,8 +3,11 @@ use bytemuck::{Pod, Zeroable};
 use futures::executor::block_on;
 use imgui::*;
 use imgui_wgpu::{Renderer, RendererConfig, Texture, TextureConfig};
-use midi::data::IntVector4;
-use midi::midifile::MIDIFile;
+use midi::data::{IntVector4, MAX_SEGMENT_LENGTH};
+use midi::filter::NoteFilter;
+use midi::midifile::{MIDIFile, MIDILoadOptions, MIDIReaderMode};
+use midi::progress::LoadProgress;
+use midi::tempo::Timeline;
 use std::fs::{self, File};
 use std::io::Read;
 use std::num::NonZeroU32;
@@ -160,16 +163,34 @@ impl Example {
             push_constant_ranges: &[],
         });
 
-        let mut midi = MIDIFile::new(
+        let progress = |p: LoadProgress| {
+            println!("{:?}: {}/{}", p.phase, p.processed, p.total);
+        };
+
+        // The demo only draws the first time segment, which has its roots at
+        // the start like the whole song used to, so it's made as long as
+        // segments can be
+        let tps = 16384;
+        let options = MIDILoadOptions {
+            segment_seconds: MAX_SEGMENT_LENGTH as f64 / tps as f64,
+            ..Default::default()
+        };
+        let mut midi = MIDIFile::new_with_options(
             "D:\\Midis\\Clubstep.mid",
-            true,
-            Some(&|read| {
-                println!("{}", read);
-            }),
+            MIDIReaderMode::Ram,
+            options,
+            Some(&progress),
         )
         .unwrap();
 
-        let mut vec = midi.parse_all_tracks(16384).expect("MIDI parse failed");
+        let mut vec = midi
+            .parse_all_tracks(
+                Timeline::Seconds { tps },
+                &NoteFilter::default(),
+                Some(&progress),
+            )
+            .expect("MIDI parse failed")
+            .swap_remove(0);
 
         let size = 8192u32;
         let height = vec.len() as u32 / size + 1;
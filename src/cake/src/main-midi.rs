@@ -1,20 +1,52 @@
-use midi::midifile::MIDIFile;
+use midi::{
+    filter::NoteFilter,
+    midifile::{MIDIFile, MIDIReaderMode},
+    progress::LoadProgress,
+    tempo::Timeline,
+};
 
 pub fn main() {
-    let midi = MIDIFile::new(
-        "D:\\Midis\\Clubstep.mid",
-        true,
-        Some(&|read| {
-            println!("{}", read);
-        }),
-    );
+    let progress = |p: LoadProgress| {
+        println!("{:?}: {}/{}", p.phase, p.processed, p.total);
+    };
+
+    let midi = MIDIFile::new("D:\\Midis\\Clubstep.mid", MIDIReaderMode::Ram, Some(&progress));
     match midi {
-        Err(_) => {
-            println!("Error loading midi")
+        Err(e) => {
+            println!("Error loading midi: {}", e)
         }
         Ok(mut file) => {
-            println!("Success! {} tracks, {} ppq", file.track_count(), file.ppq());
-            file.parse_all_tracks(16384).expect("MIDI parse failed");
+            println!(
+                "Success! {} tracks, {:?}",
+                file.track_count(),
+                file.division()
+            );
+            if let Ok(scan) = file.scan_stats() {
+                println!(
+                    "Pre-scan: {} notes, {} events, polyphony peak {}",
+                    scan.note_count,
+                    scan.event_count,
+                    scan.polyphony_peak.unwrap_or(0)
+                );
+            }
+            file.parse_all_tracks(
+                Timeline::Seconds { tps: 16384 },
+                &NoteFilter::default(),
+                Some(&progress),
+            )
+            .expect("MIDI parse failed");
+            if let Some(stats) = file.stats() {
+                println!(
+                    "{} notes, {} nodes, {:.1}s long, polyphony peak {}",
+                    stats.note_count,
+                    stats.tree_nodes.unwrap_or(0),
+                    stats.length_seconds,
+                    stats.polyphony_peak.unwrap_or(0)
+                );
+            }
+            if let Some(title) = file.metadata().as_ref().and_then(|m| m.title()) {
+                println!("Title: {}", title);
+            }
         }
     }
 }
@@ -0,0 +1,648 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    data::{IntVector4, KeyRange, MAX_SEGMENT_LENGTH, ROOTS_PER_SEGMENT},
+    errors::CacheError,
+    filter::{NoteFilter, TrackSelector},
+    midifile::{MIDIFile, MIDILoadOptions},
+    miditrack::OverlapPolicy,
+    tempo::{TempoMap, TimeDivision, Timeline},
+};
+
+/// Version of the format written by `MidiCache::save`. Caches with any other
+/// version are rejected, so this must change whenever the format or the
+/// meaning of the trees does.
+pub const CACHE_VERSION: u32 = 4;
+
+const MAGIC: &[u8; 4] = b"CAKE";
+
+/// Tree entries converted at once while reading and writing
+const CHUNK_ENTRIES: usize = 1 << 16;
+
+/// What a cache records of its source file, to tell whether it has changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceStamp {
+    pub len: u64,
+    /// Modification time in nanoseconds since the Unix epoch, or 0 if the
+    /// platform doesn't record one
+    pub modified: u64,
+    /// Hash of the contents, from `hash_source`
+    pub hash: u64,
+}
+
+impl SourceStamp {
+    /// Stamps the current contents of a file
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, CacheError> {
+        let (len, modified) = source_metadata(path.as_ref())?;
+        Ok(SourceStamp {
+            len,
+            modified,
+            hash: hash_source(path)?,
+        })
+    }
+}
+
+/// A parsed file's trees along with everything needed to show them, so that
+/// reopening the file can go straight to uploading them. Saved little-endian,
+/// starting with a magic number and `CACHE_VERSION`.
+///
+/// Every load option and filter setting that changes the trees is recorded, so
+/// `made_with` can tell whether they match a parse that's about to happen.
+#[derive(Clone)]
+pub struct MidiCache {
+    pub source: SourceStamp,
+    pub division: TimeDivision,
+    pub timeline: Timeline,
+    pub tempo_map: TempoMap,
+    /// Whether the file was parsed leniently
+    pub lenient: bool,
+    /// Key range the file was parsed with
+    pub key_range: KeyRange,
+    /// Overlap policy the file was parsed with
+    pub overlap_policy: OverlapPolicy,
+    /// Segment length the file was parsed with, in seconds
+    pub segment_seconds: f64,
+    /// Filter the file was parsed with
+    pub filter: NoteFilter,
+    /// Length of each time segment on the timeline
+    pub segment_length: i64,
+    /// The blocks of each time segment returned by `parse_all_tracks`
+    pub segments: Vec<Vec<IntVector4>>,
+}
+
+/// Hashes a file's contents with 64-bit FNV-1a, to tell whether a cache made
+/// from it is still up to date
+pub fn hash_source<P: AsRef<Path>>(path: P) -> Result<u64, CacheError> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 1 << 20];
+    let mut hash = 0xcbf29ce484222325u64;
+
+    loop {
+        let len = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for &b in &buf[..len] {
+            hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    Ok(hash)
+}
+
+/// A file's size and modification time, as recorded in a `SourceStamp`
+fn source_metadata(path: &Path) -> Result<(u64, u64), CacheError> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos() as u64);
+    Ok((metadata.len(), modified))
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    r.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f64(r: &mut impl Read) -> io::Result<f64> {
+    Ok(f64::from_bits(read_u64(r)?))
+}
+
+fn read_key_range(r: &mut impl Read) -> Result<KeyRange, CacheError> {
+    let (low, high) = (read_u8(r)?, read_u8(r)?);
+    if low > high {
+        return Err(CacheError::Corrupt);
+    }
+    Ok(KeyRange::new(low, high))
+}
+
+fn write_selectors(w: &mut impl Write, selectors: &[TrackSelector]) -> io::Result<()> {
+    w.write_all(&(selectors.len() as u32).to_le_bytes())?;
+    for selector in selectors {
+        match selector {
+            TrackSelector::Index(index) => {
+                w.write_all(&[0])?;
+                w.write_all(&index.to_le_bytes())?;
+            }
+            TrackSelector::Name(name) => {
+                w.write_all(&[1])?;
+                w.write_all(&(name.len() as u32).to_le_bytes())?;
+                w.write_all(name.as_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_selectors(r: &mut impl Read) -> Result<Vec<TrackSelector>, CacheError> {
+    let count = read_u32(r)?;
+    let mut selectors = Vec::new();
+    for _ in 0..count {
+        let selector = match read_u8(r)? {
+            0 => TrackSelector::Index(read_u32(r)?),
+            1 => {
+                // Read as it comes, so a corrupt length can't ask for an
+                // absurd allocation
+                let len = read_u32(r)? as u64;
+                let mut name = Vec::new();
+                if r.take(len).read_to_end(&mut name)? as u64 != len {
+                    return Err(CacheError::Corrupt);
+                }
+                TrackSelector::Name(String::from_utf8(name).map_err(|_| CacheError::Corrupt)?)
+            }
+            _ => return Err(CacheError::Corrupt),
+        };
+        selectors.push(selector);
+    }
+    Ok(selectors)
+}
+
+impl MidiCache {
+    /// Collects the results of the last `parse_all_tracks` call on `midi`,
+    /// which was given `filter` and returned `segments`. Returns `None` if the
+    /// file hasn't been parsed.
+    pub fn new(
+        midi: &MIDIFile,
+        source: SourceStamp,
+        filter: &NoteFilter,
+        segments: Vec<Vec<IntVector4>>,
+    ) -> Option<Self> {
+        let options = midi.options();
+        Some(MidiCache {
+            source,
+            division: *midi.division(),
+            timeline: (*midi.timeline())?,
+            tempo_map: midi.tempo_map().clone()?,
+            lenient: options.lenient,
+            key_range: options.key_range,
+            overlap_policy: options.overlap_policy,
+            segment_seconds: options.segment_seconds,
+            filter: filter.clone(),
+            segment_length: *midi.segment_length(),
+            segments,
+        })
+    }
+
+    /// Whether the trees are the ones a parse on `timeline` with `options`
+    /// and `filter` would build
+    pub fn made_with(
+        &self,
+        timeline: Timeline,
+        options: &MIDILoadOptions,
+        filter: &NoteFilter,
+    ) -> bool {
+        self.timeline == timeline
+            && self.lenient == options.lenient
+            && self.key_range == options.key_range
+            && self.overlap_policy == options.overlap_policy
+            && self.segment_seconds == options.segment_seconds
+            && self.filter == *filter
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), CacheError> {
+        let mut w = BufWriter::new(File::create(path)?);
+
+        w.write_all(MAGIC)?;
+        w.write_all(&CACHE_VERSION.to_le_bytes())?;
+        w.write_all(&self.source.len.to_le_bytes())?;
+        w.write_all(&self.source.modified.to_le_bytes())?;
+        w.write_all(&self.source.hash.to_le_bytes())?;
+
+        match self.division {
+            TimeDivision::Ppq(ppq) => {
+                w.write_all(&[0])?;
+                w.write_all(&ppq.to_le_bytes())?;
+            }
+            TimeDivision::Smpte {
+                fps,
+                ticks_per_frame,
+            } => w.write_all(&[1, fps, ticks_per_frame])?,
+        }
+        let (timeline, tps) = match self.timeline {
+            Timeline::Seconds { tps } => (0, tps),
+            Timeline::Ticks => (1, 0),
+        };
+        w.write_all(&[timeline])?;
+        w.write_all(&tps.to_le_bytes())?;
+        let overlap_policy = match self.overlap_policy {
+            OverlapPolicy::Fifo => 0,
+            OverlapPolicy::Lifo => 1,
+            OverlapPolicy::Merge => 2,
+        };
+        w.write_all(&[self.key_range.low, self.key_range.high, overlap_policy])?;
+        w.write_all(&(self.segment_length as u32).to_le_bytes())?;
+        w.write_all(&[self.lenient as u8])?;
+        w.write_all(&self.segment_seconds.to_bits().to_le_bytes())?;
+
+        let filter = &self.filter;
+        write_selectors(&mut w, &filter.include_tracks)?;
+        write_selectors(&mut w, &filter.exclude_tracks)?;
+        w.write_all(&filter.channels.to_le_bytes())?;
+        w.write_all(&[filter.keys.low, filter.keys.high, filter.min_velocity])?;
+
+        let tempo_segments = self.tempo_map.to_segments();
+        w.write_all(&(tempo_segments.len() as u64).to_le_bytes())?;
+        for (tick, seconds, seconds_per_tick) in tempo_segments {
+            w.write_all(&tick.to_le_bytes())?;
+            w.write_all(&seconds.to_bits().to_le_bytes())?;
+            w.write_all(&seconds_per_tick.to_bits().to_le_bytes())?;
+        }
+
+        w.write_all(&(self.segments.len() as u64).to_le_bytes())?;
+        let mut bytes = Vec::with_capacity(CHUNK_ENTRIES * 16);
+        for segment in &self.segments {
+            w.write_all(&(segment.len() as u64).to_le_bytes())?;
+            for chunk in segment.chunks(CHUNK_ENTRIES) {
+                bytes.clear();
+                for v in chunk {
+                    for val in &[v.val1, v.val2, v.val3, v.val4] {
+                        bytes.extend_from_slice(&val.to_le_bytes());
+                    }
+                }
+                w.write_all(&bytes)?;
+            }
+        }
+
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CacheError> {
+        let mut r = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 4];
+        if r.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            return Err(CacheError::NotACache);
+        }
+        let version = read_u32(&mut r)?;
+        if version != CACHE_VERSION {
+            return Err(CacheError::UnsupportedVersion { version });
+        }
+        let source = SourceStamp {
+            len: read_u64(&mut r)?,
+            modified: read_u64(&mut r)?,
+            hash: read_u64(&mut r)?,
+        };
+
+        let division = match read_u8(&mut r)? {
+            0 => TimeDivision::Ppq(read_u16(&mut r)?),
+            1 => TimeDivision::Smpte {
+                fps: read_u8(&mut r)?,
+                ticks_per_frame: read_u8(&mut r)?,
+            },
+            _ => return Err(CacheError::Corrupt),
+        };
+        if !division.is_valid() {
+            return Err(CacheError::Corrupt);
+        }
+        let timeline = match (read_u8(&mut r)?, read_u32(&mut r)?) {
+            (0, tps) if tps != 0 => Timeline::Seconds { tps },
+            (1, _) => Timeline::Ticks,
+            _ => return Err(CacheError::Corrupt),
+        };
+        let key_range = read_key_range(&mut r)?;
+        let overlap_policy = match read_u8(&mut r)? {
+            0 => OverlapPolicy::Fifo,
+            1 => OverlapPolicy::Lifo,
+            2 => OverlapPolicy::Merge,
+            _ => return Err(CacheError::Corrupt),
+        };
+        let segment_length = read_u32(&mut r)? as i64;
+        if segment_length == 0 || segment_length > MAX_SEGMENT_LENGTH {
+            return Err(CacheError::Corrupt);
+        }
+        let lenient = match read_u8(&mut r)? {
+            0 => false,
+            1 => true,
+            _ => return Err(CacheError::Corrupt),
+        };
+        let segment_seconds = read_f64(&mut r)?;
+
+        let filter = NoteFilter {
+            include_tracks: read_selectors(&mut r)?,
+            exclude_tracks: read_selectors(&mut r)?,
+            channels: read_u16(&mut r)?,
+            keys: read_key_range(&mut r)?,
+            min_velocity: read_u8(&mut r)?,
+        };
+
+        let tempo_len = read_u64(&mut r)?;
+        let mut tempo_segments = Vec::new();
+        for _ in 0..tempo_len {
+            let tick = read_u64(&mut r)?;
+            let seconds = read_f64(&mut r)?;
+            let seconds_per_tick = read_f64(&mut r)?;
+            tempo_segments.push((tick, seconds, seconds_per_tick));
+        }
+        let tempo_map = TempoMap::from_segments(&tempo_segments).ok_or(CacheError::Corrupt)?;
+
+        let segment_count = read_u64(&mut r)?;
+        if segment_count == 0 {
+            return Err(CacheError::Corrupt);
+        }
+        let mut segments = Vec::new();
+        let mut bytes = vec![0; CHUNK_ENTRIES * 16];
+        for _ in 0..segment_count {
+            // Indices within a block are 32-bit. Blocks grow as they're read,
+            // so a corrupt length fails at the end of the file rather than
+            // asking for an absurd allocation.
+            let len = read_u64(&mut r)?;
+            if len > i32::MAX as u64 || len < ROOTS_PER_SEGMENT as u64 {
+                return Err(CacheError::Corrupt);
+            }
+            let len = len as usize;
+            let mut block = Vec::new();
+            while block.len() < len {
+                let count = (len - block.len()).min(CHUNK_ENTRIES);
+                let bytes = &mut bytes[..count * 16];
+                r.read_exact(bytes)?;
+                block.reserve(count);
+                for entry in bytes.chunks_exact(16) {
+                    let val = |i: usize| {
+                        let mut b = [0; 4];
+                        b.copy_from_slice(&entry[i * 4..i * 4 + 4]);
+                        i32::from_le_bytes(b)
+                    };
+                    block.push(IntVector4 {
+                        val1: val(0),
+                        val2: val(1),
+                        val3: val(2),
+                        val4: val(3),
+                    });
+                }
+            }
+            segments.push(block);
+        }
+
+        Ok(MidiCache {
+            source,
+            division,
+            timeline,
+            tempo_map,
+            lenient,
+            key_range,
+            overlap_policy,
+            segment_seconds,
+            filter,
+            segment_length,
+            segments,
+        })
+    }
+
+    /// Loads a cache, checking that it was made from the current contents of
+    /// `source`. A source with the size and modification time it was cached
+    /// with is taken to be unchanged, so only sources that were touched since
+    /// are hashed.
+    pub fn load_for<P: AsRef<Path>, S: AsRef<Path>>(
+        path: P,
+        source: S,
+    ) -> Result<Self, CacheError> {
+        let cache = MidiCache::load(path)?;
+        let (len, modified) = source_metadata(source.as_ref())?;
+        if len != cache.source.len {
+            return Err(CacheError::SourceChanged);
+        }
+        if (modified == 0 || modified != cache.source.modified)
+            && hash_source(source)? != cache.source.hash
+        {
+            return Err(CacheError::SourceChanged);
+        }
+        Ok(cache)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, path::PathBuf};
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("cake-{}-{}.cake", std::process::id(), name))
+    }
+
+    fn entry(i: i32) -> IntVector4 {
+        IntVector4 {
+            val1: i,
+            val2: -i,
+            val3: i * 3,
+            val4: i32::MAX - i,
+        }
+    }
+
+    fn cache() -> MidiCache {
+        let tempo = [(0, 0.0, 0.5 / 480.0), (1920, 2.0, 0.25 / 480.0)];
+        MidiCache {
+            source: SourceStamp {
+                len: 1234,
+                modified: 1_600_000_000_000_000_000,
+                hash: 0x0123_4567_89ab_cdef,
+            },
+            division: TimeDivision::Ppq(480),
+            timeline: Timeline::Seconds { tps: 1000 },
+            tempo_map: TempoMap::from_segments(&tempo).unwrap(),
+            lenient: true,
+            key_range: KeyRange::PIANO,
+            overlap_policy: OverlapPolicy::Lifo,
+            segment_seconds: 2.5,
+            filter: NoteFilter {
+                include_tracks: vec![TrackSelector::Index(3), TrackSelector::Name("Piano".into())],
+                exclude_tracks: vec![TrackSelector::Name(String::new())],
+                channels: 0x0203,
+                keys: KeyRange::new(30, 90),
+                min_velocity: 12,
+            },
+            segment_length: 10_000,
+            segments: vec![
+                (0..ROOTS_PER_SEGMENT as i32).map(entry).collect(),
+                (0..CHUNK_ENTRIES as i32 + 300).map(entry).collect(),
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round-trip");
+        let saved = cache();
+        saved.save(&path).unwrap();
+        let loaded = MidiCache::load(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.source, saved.source);
+        assert_eq!(loaded.division, saved.division);
+        assert_eq!(loaded.timeline, saved.timeline);
+        assert_eq!(
+            loaded.tempo_map.to_segments(),
+            saved.tempo_map.to_segments()
+        );
+        assert_eq!(loaded.lenient, saved.lenient);
+        assert_eq!(loaded.key_range, saved.key_range);
+        assert_eq!(loaded.overlap_policy, saved.overlap_policy);
+        assert_eq!(loaded.segment_seconds, saved.segment_seconds);
+        assert_eq!(loaded.filter, saved.filter);
+        assert_eq!(loaded.segment_length, saved.segment_length);
+        assert_eq!(loaded.segments.len(), saved.segments.len());
+        for (loaded, saved) in loaded.segments.iter().zip(&saved.segments) {
+            let fields = |v: &IntVector4| (v.val1, v.val2, v.val3, v.val4);
+            assert!(loaded.iter().map(fields).eq(saved.iter().map(fields)));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_caches() {
+        let path = temp_path("invalid");
+        cache().save(&path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let load = |edit: &dyn Fn(&mut Vec<u8>)| {
+            let mut bytes = bytes.clone();
+            edit(&mut bytes);
+            fs::write(&path, bytes).unwrap();
+            MidiCache::load(&path)
+        };
+
+        // The timeline's ticks per second follow the magic, version, source
+        // stamp and PPQ division, and the segment length follows the keys and
+        // policy. Then come the lenient flag, the segment seconds and the
+        // count of included tracks.
+        let ppq = 4 + 4 + 24 + 1;
+        let tps = ppq + 2 + 1;
+        let segment_length = tps + 4 + 3;
+        let lenient = segment_length + 4;
+        let include_tracks = lenient + 1 + 8;
+        let results = [
+            load(&|b| b[0] = b'X'),
+            load(&|b| b[4] = CACHE_VERSION as u8 + 1),
+            load(&|b| b[ppq..ppq + 2].copy_from_slice(&[0, 0])),
+            load(&|b| b[tps..tps + 4].copy_from_slice(&[0; 4])),
+            load(&|b| b[segment_length..segment_length + 4].copy_from_slice(&[0; 4])),
+            load(&|b| b[lenient] = 2),
+            load(&|b| b[include_tracks..include_tracks + 4].copy_from_slice(&[0xFF; 4])),
+            load(&|b| b.truncate(b.len() - 1)),
+        ];
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(results[0], Err(CacheError::NotACache)));
+        assert!(matches!(
+            results[1],
+            Err(CacheError::UnsupportedVersion { .. })
+        ));
+        for result in &results[2..] {
+            assert!(matches!(result, Err(CacheError::Corrupt)));
+        }
+    }
+
+    #[test]
+    fn matches_load_settings() {
+        let cache = cache();
+        let options = MIDILoadOptions {
+            lenient: true,
+            key_range: KeyRange::PIANO,
+            overlap_policy: OverlapPolicy::Lifo,
+            segment_seconds: 2.5,
+            ..Default::default()
+        };
+        let timeline = Timeline::Seconds { tps: 1000 };
+        assert!(cache.made_with(timeline, &options, &cache.filter));
+
+        assert!(!cache.made_with(Timeline::Ticks, &options, &cache.filter));
+        assert!(!cache.made_with(timeline, &options, &NoteFilter::default()));
+        let changed = [
+            MIDILoadOptions {
+                lenient: false,
+                ..options.clone()
+            },
+            MIDILoadOptions {
+                key_range: KeyRange::STANDARD,
+                ..options.clone()
+            },
+            MIDILoadOptions {
+                overlap_policy: OverlapPolicy::Merge,
+                ..options.clone()
+            },
+            MIDILoadOptions {
+                segment_seconds: 5.0,
+                ..options.clone()
+            },
+        ];
+        for options in &changed {
+            assert!(!cache.made_with(timeline, options, &cache.filter));
+        }
+
+        // Options that don't change the trees don't matter
+        let budgeted = MIDILoadOptions {
+            memory_budget: 1,
+            ..options
+        };
+        assert!(cache.made_with(timeline, &budgeted, &cache.filter));
+    }
+
+    #[test]
+    fn checks_the_source() {
+        let path = temp_path("checked");
+        let source = temp_path("source");
+        fs::write(&source, b"MThd and the rest").unwrap();
+        let stamp = SourceStamp::read(&source).unwrap();
+        let load = |source_stamp: SourceStamp| {
+            let mut cache = cache();
+            cache.source = source_stamp;
+            cache.save(&path).unwrap();
+            MidiCache::load_for(&path, &source)
+        };
+
+        let results = [
+            load(stamp),
+            // The same size and time are trusted without hashing
+            load(SourceStamp { hash: 0, ..stamp }),
+            // Otherwise the contents have to match
+            load(SourceStamp {
+                modified: stamp.modified + 1,
+                ..stamp
+            }),
+            load(SourceStamp {
+                modified: stamp.modified + 1,
+                hash: 0,
+                ..stamp
+            }),
+            load(SourceStamp {
+                len: stamp.len + 1,
+                ..stamp
+            }),
+        ];
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&source).unwrap();
+
+        assert_eq!(stamp.len, 17);
+        assert!(results[..3].iter().all(|r| r.is_ok()));
+        for result in &results[3..] {
+            assert!(matches!(result, Err(CacheError::SourceChanged)));
+        }
+    }
+}
@@ -1,14 +1,135 @@
 use bytemuck::{Pod, Zeroable};
 use color::{Deg, Hsv, ToRgb};
 use std::{
-    borrow::Borrow,
     cmp::{max, min},
     collections::{LinkedList, VecDeque},
-    rc::Rc,
 };
 
+/// Longest a time segment of the serialized trees can be. Times within a
+/// segment are stored relative to its start so they fit in 32 bits, while the
+/// song itself can be as long as 64-bit time allows.
+pub const MAX_SEGMENT_LENGTH: i64 = 1 << 29;
+
+/// Number of tree roots at the start of each segment, one per key
+pub const ROOTS_PER_SEGMENT: usize = 256;
+
+/// Most time segments a song can be split into. Every segment takes at least
+/// a root and an empty leaf per key, even with no notes, so this keeps those
+/// within 256 MiB.
+pub const MAX_SEGMENTS: i64 =
+    (256 << 20) / (2 * ROOTS_PER_SEGMENT * std::mem::size_of::<IntVector4>()) as i64;
+
+/// Relative times are clamped to this, which is far enough outside any segment
+/// that clamped notes and cutoffs still compare the same way
+const RELATIVE_TIME_LIMIT: i64 = 2 * MAX_SEGMENT_LENGTH;
+
+/// Splits a time into its segment and the time relative to that segment, for
+/// segments `length` long
+pub fn segment_time(time: i64, length: i64) -> (i64, i32) {
+    debug_assert!(length > 0 && length <= MAX_SEGMENT_LENGTH);
+    let segment = time.div_euclid(length);
+    (segment, time.rem_euclid(length) as i32)
+}
+
+fn relative_time(time: i64, base: i64) -> i32 {
+    time.saturating_sub(base)
+        .clamp(-RELATIVE_TIME_LIMIT, RELATIVE_TIME_LIMIT) as i32
+}
+
+/// An inclusive range of keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyRange {
+    pub low: u8,
+    pub high: u8,
+}
+
+impl KeyRange {
+    /// The 88 keys of a piano
+    pub const PIANO: KeyRange = KeyRange { low: 21, high: 108 };
+    /// Every key standard MIDI can address
+    pub const STANDARD: KeyRange = KeyRange { low: 0, high: 127 };
+    /// All 256 keys, for files that use key numbers past 127
+    pub const EXTENDED: KeyRange = KeyRange { low: 0, high: 255 };
+
+    pub fn new(low: u8, high: u8) -> Self {
+        assert!(low <= high, "key range {}..={} is empty", low, high);
+        KeyRange { low, high }
+    }
+
+    pub fn len(&self) -> u32 {
+        (self.high as u32 + 1).saturating_sub(self.low as u32)
+    }
+
+    /// Whether the range holds no keys, which only ranges built from their
+    /// fields with `high` below `low` do
+    pub fn is_empty(&self) -> bool {
+        self.high < self.low
+    }
+
+    pub fn contains(&self, key: u8) -> bool {
+        self.low <= key && key <= self.high
+    }
+}
+
+impl Default for KeyRange {
+    fn default() -> Self {
+        KeyRange::STANDARD
+    }
+}
+
+/// The notes of a single key, shared by the trees of all its segments. Trees
+/// refer to notes by their index, so a note can be given to them before its
+/// end is known and ended later.
+#[derive(Default)]
+pub struct NoteArena {
+    notes: Vec<Note>,
+}
+
+impl NoteArena {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn push(&mut self, note: Note) -> usize {
+        self.notes.push(note);
+        self.notes.len() - 1
+    }
+
+    pub fn get(&self, index: usize) -> &Note {
+        &self.notes[index]
+    }
+
+    pub fn end_note(&mut self, index: usize, time: i64) {
+        self.notes[index].end = time;
+    }
+
+    pub fn len(&self) -> usize {
+        self.notes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notes.is_empty()
+    }
+}
+
+/// A note's end, treating notes that haven't ended yet as never ending
+fn known_end(note: &Note) -> i64 {
+    match note.unended() {
+        true => i64::MAX,
+        false => note.end,
+    }
+}
+
+/// Whether two leaves show the same note. Notes that haven't ended only match
+/// themselves, as their ends may still turn out different.
+fn same_note(notes: &NoteArena, a: usize, b: usize) -> bool {
+    let (first, second) = (notes.get(a), notes.get(b));
+    a == b || (!first.unended() && !second.unended() && first.equals(second))
+}
+
 pub enum Leaf {
-    Note(Option<Rc<Note>>),
+    /// Index of the note in the key's `NoteArena`
+    Note(Option<usize>),
     Node(Node),
 }
 
@@ -26,14 +147,16 @@ impl Leaf {
         }
     }
 
-    pub fn serialize_to_vec(&self, vec: &mut Vec<IntVector4>) -> i32 {
+    /// Appends the tree to `vec` with its times relative to `base`, returning
+    /// the index of its root
+    pub fn serialize_to_vec(&self, vec: &mut Vec<IntVector4>, base: i64, notes: &NoteArena) -> i32 {
         match &self {
             &Leaf::Node(node) => {
-                let lower = node.lower.serialize_to_vec(vec);
-                let upper = node.upper.serialize_to_vec(vec);
+                let lower = node.lower.serialize_to_vec(vec, base, notes);
+                let upper = node.upper.serialize_to_vec(vec, base, notes);
 
                 vec.push(IntVector4 {
-                    val1: node.cutoff,
+                    val1: relative_time(node.cutoff, base),
                     val2: lower,
                     val3: upper,
                     val4: 0,
@@ -49,12 +172,15 @@ impl Leaf {
                         val3: -1,
                         val4: 0,
                     },
-                    Some(note) => IntVector4 {
-                        val1: note.start,
-                        val2: note.end,
-                        val3: get_col(note.color),
-                        val4: note.note_num,
-                    },
+                    Some(index) => {
+                        let note = notes.get(*index);
+                        IntVector4 {
+                            val1: relative_time(note.start, base),
+                            val2: relative_time(note.end, base),
+                            val3: get_col(note.color),
+                            val4: note.velocity as i32,
+                        }
+                    }
                 });
 
                 -(vec.len() as i32 - 1)
@@ -84,30 +210,38 @@ impl IntVector4 {
 }
 
 pub struct Note {
-    pub start: i32,
-    pub end: i32,
+    pub start: i64,
+    pub end: i64,
     pub color: i32,
-    pub note_num: i32,
+    pub velocity: u8,
 }
 
 impl Note {
-    const UNENDED: i32 = -1;
+    const UNENDED: i64 = -1;
 
     fn encode_color(track: u32, channel: u8) -> i32 {
         track as i32 * 16 + channel as i32
     }
 
-    pub fn new(start: i32, end: i32, track: u32, channel: u8) -> Self {
+    pub fn new(start: i64, end: i64, track: u32, channel: u8, velocity: u8) -> Self {
         Note {
             start,
             end,
             color: Note::encode_color(track, channel),
-            note_num: 0,
+            velocity,
         }
     }
 
-    pub fn new_unended(start: i32, track: u32, channel: u8) -> Self {
-        Note::new(start, Note::UNENDED, track, channel)
+    pub fn new_unended(start: i64, track: u32, channel: u8, velocity: u8) -> Self {
+        Note::new(start, Note::UNENDED, track, channel, velocity)
+    }
+
+    pub fn track(&self) -> u32 {
+        (self.color / 16) as u32
+    }
+
+    pub fn channel(&self) -> u8 {
+        (self.color % 16) as u8
     }
 
     pub fn unended(&self) -> bool {
@@ -115,42 +249,45 @@ impl Note {
     }
 
     pub fn equals(&self, other: &Note) -> bool {
-        self.start == other.start && self.end == other.end && self.color == other.color
+        self.start == other.start
+            && self.end == other.end
+            && self.color == other.color
+            && self.velocity == other.velocity
     }
 }
 
 pub struct Node {
-    cutoff: i32,
+    cutoff: i64,
     upper: Box<Leaf>,
     lower: Box<Leaf>,
 }
 
 struct FetchingFirst {
-    start: i32,
-    half: i32,
-    end: i32,
+    start: i64,
+    half: i64,
+    end: i64,
 }
 
 struct FetchingSecond {
     first: Leaf,
-    half: i32,
-    end: i32,
+    half: i64,
+    end: i64,
 }
 
 enum SerializerFrame {
     FetchingFirst(FetchingFirst),
     FetchingSecond(FetchingSecond),
-    FetchingNote(i32),
+    FetchingNote(i64),
 }
 
 enum SerializerInput {
     Init,
-    Note(Rc<Note>),
+    Note(usize),
     End,
 }
 
 impl FetchingFirst {
-    pub fn to_second(&self, first: Leaf, next_event: i32) -> SerializerFrame {
+    pub fn to_second(&self, first: Leaf, next_event: i64) -> SerializerFrame {
         debug_assert!(next_event < self.end);
 
         SerializerFrame::FetchingSecond(FetchingSecond {
@@ -162,7 +299,7 @@ impl FetchingFirst {
 }
 
 impl SerializerFrame {
-    pub fn new(start: i32, end: i32) -> SerializerFrame {
+    pub fn new(start: i64, end: i64) -> SerializerFrame {
         if end - start == 1 {
             SerializerFrame::FetchingNote(start)
         } else {
@@ -175,42 +312,54 @@ impl SerializerFrame {
     }
 }
 
+/// Builds a key's tree from notes fed in order of their start times. Notes can
+/// be fed before they have ended, as long as each one is ended in the arena
+/// before any note starting after its end is fed.
 pub struct TreeSerializer {
-    note_stack: LinkedList<Rc<Note>>,
-    next_note: Option<Rc<Note>>,
+    note_stack: LinkedList<usize>,
+    next_note: Option<usize>,
     ended: bool,
     stack_frames: VecDeque<SerializerFrame>,
-    fed_up_to: i32,
-    parsed_up_to: i32,
+    fed_up_to: i64,
+    parsed_up_to: i64,
+    origin: i64,
 
     final_leaf: Option<Leaf>,
 }
 
 impl TreeSerializer {
-    pub fn new(initial_end: i32) -> Self {
+    pub fn new(initial_end: i64) -> Self {
+        TreeSerializer::new_from(0, initial_end)
+    }
+
+    /// Creates a serializer for a tree starting at `origin` instead of 0. Notes
+    /// that started earlier can still be fed, and are treated as already
+    /// playing at `origin`.
+    pub fn new_from(origin: i64, initial_len: i64) -> Self {
         let mut stack_frames = VecDeque::<SerializerFrame>::new();
-        stack_frames.push_front(SerializerFrame::new(0, initial_end));
+        stack_frames.push_front(SerializerFrame::new(origin, origin + initial_len));
         let mut serializer = TreeSerializer {
             ended: false,
             note_stack: LinkedList::new(),
             next_note: None,
             stack_frames,
-            fed_up_to: 0,
-            parsed_up_to: 0,
+            fed_up_to: origin,
+            parsed_up_to: origin,
+            origin,
 
             final_leaf: None,
         };
-        serializer.run_state_machine(SerializerInput::Init);
+        serializer.run_state_machine(&NoteArena::new(), SerializerInput::Init);
 
         serializer
     }
 
-    fn clean_note_stack_fast(&mut self, time: i32) {
+    fn clean_note_stack_fast(&mut self, notes: &NoteArena, time: i64) {
         loop {
             match self.note_stack.front() {
                 None => break,
-                Some(note) => {
-                    if note.end > time {
+                Some(&note) => {
+                    if known_end(notes.get(note)) > time {
                         break;
                     }
                 }
@@ -219,45 +368,53 @@ impl TreeSerializer {
         }
     }
 
-    fn max_parse_dist(&self) -> i32 {
-        match &self.next_note {
+    fn max_parse_dist(&self, notes: &NoteArena) -> i64 {
+        match self.next_note {
             None => {
                 if self.ended {
-                    i32::MAX
+                    i64::MAX
                 } else {
                     0
                 }
             }
-            Some(n) => n.start,
+            Some(n) => notes.get(n).start,
         }
     }
 
-    pub fn feed_note(&mut self, note: Rc<Note>) {
-        self.run_state_machine(SerializerInput::Note(note));
+    /// Feeds the note at `note` in `notes`, which must start no earlier than
+    /// the notes fed before it
+    pub fn feed_note(&mut self, notes: &NoteArena, note: usize) {
+        self.run_state_machine(notes, SerializerInput::Note(note));
     }
 
-    pub fn complete(mut self) -> Leaf {
-        self.run_state_machine(SerializerInput::End);
+    /// Finishes the tree. Every note fed must have ended by now.
+    pub fn complete(mut self, notes: &NoteArena) -> Leaf {
+        self.run_state_machine(notes, SerializerInput::End);
         return self.final_leaf.expect("Final leaf not received");
     }
 
-    fn next_event(&self) -> i32 {
-        let next_start = match &self.next_note {
-            None => i32::MAX,
-            Some(n) => n.start,
+    fn next_event(&self, notes: &NoteArena) -> i64 {
+        let next_start = match self.next_note {
+            None => i64::MAX,
+            Some(n) => notes.get(n).start,
         };
         let next_end = match self.note_stack.front() {
-            None => i32::MAX,
-            Some(n) => n.end,
+            None => i64::MAX,
+            Some(&n) => known_end(notes.get(n)),
         };
 
         min(next_start, next_end)
     }
 
-    fn run_state_machine(&mut self, new_event: SerializerInput) {
+    fn run_state_machine(&mut self, notes: &NoteArena, new_event: SerializerInput) {
         if let Some(n) = self.next_note.take() {
-            self.fed_up_to = n.start;
-            self.clean_note_stack_fast(n.end);
+            let note = notes.get(n);
+            self.fed_up_to = note.start;
+            // Notes it hides that end first will never show again, which an
+            // unended note can't tell yet
+            if !note.unended() {
+                self.clean_note_stack_fast(notes, note.end);
+            }
             self.note_stack.push_front(n);
         }
 
@@ -274,7 +431,7 @@ impl TreeSerializer {
         };
 
         // Maximum position it can
-        let max_parse_dist = self.max_parse_dist();
+        let max_parse_dist = self.max_parse_dist(notes);
 
         loop {
             if !skip_returns {
@@ -296,23 +453,21 @@ impl TreeSerializer {
                 // because FetchingNote's end is `pos + 1`
                 self.parsed_up_to = pos + 1;
 
-                let top_note = match self.note_stack.front() {
-                    None => None,
-                    Some(n) => Some(n.clone()),
-                };
+                let top_note = self.note_stack.front().copied();
 
                 self.stack_frames.pop_front();
 
                 // Pop stack frames
                 let mut ret = Leaf::Note(top_note);
-                let next_event = self.next_event();
+                let next_event = self.next_event(notes);
                 loop {
                     if self.stack_frames.len() == 0 {
                         if self.ended && self.note_stack.len() == 0 {
                             self.final_leaf = Some(ret);
                             return;
                         }
-                        let frame = SerializerFrame::new(0, max(next_event, self.parsed_up_to) * 2);
+                        let len = max(next_event, self.parsed_up_to) - self.origin;
+                        let frame = SerializerFrame::new(self.origin, self.origin + len * 2);
                         self.stack_frames.push_front(frame);
                     }
 
@@ -333,7 +488,7 @@ impl TreeSerializer {
                                 if let Leaf::Note(s) = &ret {
                                     if let Some(f) = f {
                                         if let Some(s) = s {
-                                            if s.equals(f.borrow()) {
+                                            if same_note(notes, *f, *s) {
                                                 ret = first;
                                                 continue;
                                             }
@@ -375,7 +530,87 @@ impl TreeSerializer {
                 self.stack_frames
                     .push_front(SerializerFrame::new(start, end));
             }
-            self.clean_note_stack_fast(final_start);
+            self.clean_note_stack_fast(notes, final_start);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_times() {
+        assert_eq!(segment_time(0, 100), (0, 0));
+        assert_eq!(segment_time(99, 100), (0, 99));
+        assert_eq!(segment_time(100, 100), (1, 0));
+        assert_eq!(segment_time(-1, 100), (-1, 99));
+        assert_eq!(segment_time(-100, 100), (-1, 0));
+        assert_eq!(segment_time(-101, 100), (-2, 99));
+
+        let length = MAX_SEGMENT_LENGTH;
+        assert_eq!(segment_time(length - 1, length), (0, length as i32 - 1));
+        let last = (i64::MAX / length, length as i32 - 1);
+        assert_eq!(segment_time(i64::MAX, length), last);
+    }
+
+    #[test]
+    fn relative_times() {
+        let limit = RELATIVE_TIME_LIMIT as i32;
+        assert_eq!(relative_time(150, 100), 50);
+        assert_eq!(relative_time(50, 100), -50);
+        assert_eq!(relative_time(100 + RELATIVE_TIME_LIMIT, 100), limit);
+        assert_eq!(relative_time(101 + RELATIVE_TIME_LIMIT, 100), limit);
+        assert_eq!(relative_time(100 - RELATIVE_TIME_LIMIT, 100), -limit);
+        assert_eq!(relative_time(99 - RELATIVE_TIME_LIMIT, 100), -limit);
+        assert_eq!(relative_time(i64::MAX, MAX_SEGMENT_LENGTH), limit);
+        assert_eq!(relative_time(i64::MIN, MAX_SEGMENT_LENGTH), -limit);
+    }
+
+    /// The start and end of the note a serialized tree shows at `time`
+    fn note_at(tree: &[IntVector4], root: i32, base: i64, time: i64) -> Option<(i64, i64)> {
+        let time = relative_time(time, base);
+        let mut next = root;
+        while next > 0 {
+            let node = tree[next as usize];
+            next = match time < node.val1 {
+                true => node.val2,
+                false => node.val3,
+            };
+        }
+        let leaf = tree[-next as usize];
+        match leaf.val3 {
+            -1 => None,
+            _ => Some((leaf.val1 as i64 + base, leaf.val2 as i64 + base)),
+        }
+    }
+
+    #[test]
+    fn notes_across_segments() {
+        let mut notes = NoteArena::new();
+        let long = notes.push(Note::new(50, 350, 0, 0, 100));
+        let short = notes.push(Note::new(180, 220, 0, 0, 100));
+
+        for segment in 0..4 {
+            let origin = segment * 100;
+            let mut serializer = TreeSerializer::new_from(origin, 4);
+            serializer.feed_note(&notes, long);
+            if segment == 1 || segment == 2 {
+                serializer.feed_note(&notes, short);
+            }
+
+            // Index 0 is taken, as it is by the roots of a block
+            let mut tree = vec![IntVector4::default()];
+            let leaf = serializer.complete(&notes);
+            let root = leaf.serialize_to_vec(&mut tree, origin, &notes);
+            for time in origin..origin + 100 {
+                let expected = match time {
+                    180..=219 => Some((180, 220)),
+                    50..=349 => Some((50, 350)),
+                    _ => None,
+                };
+                assert_eq!(note_at(&tree, root, origin, time), expected, "at {}", time);
+            }
         }
     }
 }
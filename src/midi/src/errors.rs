@@ -1,9 +1,273 @@
+use std::{error::Error, fmt, io};
+
 #[derive(Debug)]
 pub enum MIDILoadError {
-    NotFound,
-    CorruptChunks,
+    /// The file could not be opened
+    NotFound(io::Error),
+    /// Reading from the file failed, at the given byte offset if known
+    FilesystemError {
+        offset: Option<u64>,
+        source: io::Error,
+    },
+    /// A chunk started with a different id than the one expected
+    UnexpectedChunk {
+        expected: String,
+        found: String,
+        offset: u64,
+    },
+    /// The data ended in the middle of a chunk header or event
+    UnexpectedEnd {
+        offset: u64,
+    },
+    /// The `MThd` chunk declared a length other than 6
+    InvalidHeaderLength {
+        length: u32,
+        offset: u64,
+    },
+    /// A chunk claims to extend past the end of the file
+    ChunkOutOfBounds {
+        offset: u64,
+        len: u64,
+        file_len: u64,
+    },
     Format2MIDI,
-    UnknownFilesystemError,
+    /// The header declared a format other than 0, 1 or 2
+    UnknownFormat {
+        format: u16,
+        offset: u64,
+    },
+    /// The header's time division was zero or had an unknown SMPTE frame rate
+    InvalidDivision {
+        offset: u64,
+    },
+    /// A track index past the number of tracks was requested
+    TrackNotFound {
+        track: u32,
+    },
+    /// A track reader reached the end of its chunk. Used internally to end
+    /// tracks that have no end-of-track event.
     OutOfBoundsError,
+    /// The song has more than `MAX_SEGMENTS` time segments, or a segment has
+    /// more tree entries than its 32-bit indices can reach
     MIDITooLong,
+    /// The load was cancelled through its `CancelToken`
+    Cancelled,
+    /// `MIDILoadOptions::segment_seconds` wasn't a positive, normal number
+    InvalidSegmentSeconds {
+        seconds: f64,
+    },
+    /// An error raised while parsing a track, with the track's index and the
+    /// byte offset the track reader had reached
+    TrackError {
+        track: u32,
+        offset: u64,
+        source: Box<MIDILoadError>,
+    },
+}
+
+impl MIDILoadError {
+    pub(crate) fn from_io(source: io::Error, offset: u64) -> Self {
+        match source.kind() {
+            io::ErrorKind::UnexpectedEof => MIDILoadError::UnexpectedEnd { offset },
+            _ => MIDILoadError::FilesystemError {
+                offset: Some(offset),
+                source,
+            },
+        }
+    }
+
+    pub(crate) fn in_track(self, track: u32, offset: u64) -> Self {
+        match self {
+            // Already has its context, or isn't about the track
+            e @ MIDILoadError::TrackError { .. } | e @ MIDILoadError::Cancelled => e,
+            e => MIDILoadError::TrackError {
+                track,
+                offset,
+                source: Box::new(e),
+            },
+        }
+    }
+}
+
+impl From<io::Error> for MIDILoadError {
+    fn from(source: io::Error) -> Self {
+        MIDILoadError::FilesystemError {
+            offset: None,
+            source,
+        }
+    }
+}
+
+impl fmt::Display for MIDILoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MIDILoadError::NotFound(e) => write!(f, "could not open file: {}", e),
+            MIDILoadError::FilesystemError {
+                offset: Some(offset),
+                source,
+            } => write!(f, "failed to read file at byte {}: {}", offset, source),
+            MIDILoadError::FilesystemError {
+                offset: None,
+                source,
+            } => write!(f, "failed to read file: {}", source),
+            MIDILoadError::UnexpectedChunk {
+                expected,
+                found,
+                offset,
+            } => write!(
+                f,
+                "unexpected chunk id '{}' at byte {}, expected '{}'",
+                found, offset, expected
+            ),
+            MIDILoadError::UnexpectedEnd { offset } => {
+                write!(f, "file is truncated, data ends at byte {}", offset)
+            }
+            MIDILoadError::InvalidHeaderLength { length, offset } => write!(
+                f,
+                "header chunk at byte {} has length {}, expected 6",
+                offset, length
+            ),
+            MIDILoadError::ChunkOutOfBounds {
+                offset,
+                len,
+                file_len,
+            } => write!(
+                f,
+                "chunk at byte {} with length {} extends past the end of the file ({} bytes)",
+                offset, len, file_len
+            ),
+            MIDILoadError::Format2MIDI => write!(f, "format 2 MIDI files are not supported"),
+            MIDILoadError::UnknownFormat { format, offset } => {
+                write!(f, "unknown MIDI format {} at byte {}", format, offset)
+            }
+            MIDILoadError::InvalidDivision { offset } => {
+                write!(f, "invalid time division at byte {}", offset)
+            }
+            MIDILoadError::TrackNotFound { track } => write!(f, "track {} doesn't exist", track),
+            MIDILoadError::OutOfBoundsError => write!(f, "read past the end of a track"),
+            MIDILoadError::MIDITooLong => write!(f, "MIDI is too long for the timeline"),
+            MIDILoadError::Cancelled => write!(f, "loading was cancelled"),
+            MIDILoadError::InvalidSegmentSeconds { seconds } => {
+                write!(f, "invalid segment length of {} seconds", seconds)
+            }
+            MIDILoadError::TrackError {
+                track,
+                offset,
+                source,
+            } => write!(f, "track {} (byte {}): {}", track, offset, source),
+        }
+    }
+}
+
+impl Error for MIDILoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MIDILoadError::NotFound(e) => Some(e),
+            MIDILoadError::FilesystemError { source, .. } => Some(source),
+            MIDILoadError::TrackError { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// A problem that was worked around while loading, instead of failing the load
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadWarning {
+    /// A track's declared length didn't end on the next chunk or the end of the
+    /// file, so its length was corrected to `actual`
+    ChunkLengthMismatch {
+        track: u32,
+        offset: u64,
+        declared: u32,
+        actual: u64,
+    },
+    /// Data that isn't a chunk was skipped
+    SkippedData { offset: u64, len: u64 },
+    /// A track ran out of data without an end-of-track event
+    MissingEndOfTrack { track: u32 },
+    /// The header declared a different number of tracks than were found
+    TrackCountMismatch { declared: u32, found: u32 },
+    /// The header declared an unknown format, so the file was read as format 1
+    UnknownFormat { format: u16 },
+}
+
+impl fmt::Display for LoadWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadWarning::ChunkLengthMismatch {
+                track,
+                offset,
+                declared,
+                actual,
+            } => write!(
+                f,
+                "track {} at byte {} declares length {} but is {} bytes long",
+                track, offset, declared, actual
+            ),
+            LoadWarning::SkippedData { offset, len } => {
+                write!(
+                    f,
+                    "skipped {} bytes of unknown data at byte {}",
+                    len, offset
+                )
+            }
+            LoadWarning::MissingEndOfTrack { track } => {
+                write!(f, "track {} has no end-of-track event", track)
+            }
+            LoadWarning::TrackCountMismatch { declared, found } => write!(
+                f,
+                "header declares {} tracks but {} were found",
+                declared, found
+            ),
+            LoadWarning::UnknownFormat { format } => {
+                write!(f, "unknown MIDI format {}, reading as format 1", format)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CacheError {
+    /// Reading or writing the cache or its source file failed
+    Io(io::Error),
+    /// The file doesn't start like a cache file
+    NotACache,
+    /// The cache was written in a format version this build can't read
+    UnsupportedVersion { version: u32 },
+    /// The cache ended early or holds values that can't be loaded
+    Corrupt,
+    /// The source file changed after the cache was written
+    SourceChanged,
+}
+
+impl From<io::Error> for CacheError {
+    fn from(source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::UnexpectedEof => CacheError::Corrupt,
+            _ => CacheError::Io(source),
+        }
+    }
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::Io(e) => write!(f, "cache file error: {}", e),
+            CacheError::NotACache => write!(f, "not a cache file"),
+            CacheError::UnsupportedVersion { version } => {
+                write!(f, "unsupported cache version {}", version)
+            }
+            CacheError::Corrupt => write!(f, "cache file is corrupt"),
+            CacheError::SourceChanged => write!(f, "source file changed since it was cached"),
+        }
+    }
+}
+
+impl Error for CacheError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CacheError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
 }
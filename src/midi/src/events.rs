@@ -0,0 +1,158 @@
+/// The kind of a text meta event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextKind {
+    Text,
+    Copyright,
+    TrackName,
+    InstrumentName,
+    Lyric,
+    Marker,
+    CuePoint,
+}
+
+impl TextKind {
+    fn from_meta(kind: u8) -> Option<Self> {
+        match kind {
+            0x01 => Some(TextKind::Text),
+            0x02 => Some(TextKind::Copyright),
+            0x03 => Some(TextKind::TrackName),
+            0x04 => Some(TextKind::InstrumentName),
+            0x05 => Some(TextKind::Lyric),
+            0x06 => Some(TextKind::Marker),
+            0x07 => Some(TextKind::CuePoint),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSignature {
+    pub numerator: u8,
+    /// The actual denominator, rather than the power of two stored in the file
+    pub denominator: u32,
+    /// MIDI clocks per metronome click
+    pub clocks_per_click: u8,
+    pub notated_32nds_per_quarter: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeySignature {
+    /// Number of sharps, or flats if negative
+    pub sharps: i8,
+    pub minor: bool,
+}
+
+/// A single event from a track, as stored in the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MidiEvent {
+    NoteOff {
+        channel: u8,
+        key: u8,
+        velocity: u8,
+    },
+    /// A note on. A velocity of 0 is kept as-is, even though it acts as a note off.
+    NoteOn {
+        channel: u8,
+        key: u8,
+        velocity: u8,
+    },
+    PolyphonicAftertouch {
+        channel: u8,
+        key: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelAftertouch {
+        channel: u8,
+        pressure: u8,
+    },
+    /// Pitch bend, where 0x2000 is centered
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+    /// An `F0` system exclusive message, without the leading `F0`
+    SystemExclusive(Vec<u8>),
+    /// An `F7` escape, carrying arbitrary bytes
+    Escape(Vec<u8>),
+    SongPosition(u16),
+    SongSelect(u8),
+    /// Tempo in microseconds per quarter note
+    Tempo(u32),
+    /// A text meta event. The bytes are kept as-is, as files use all sorts of
+    /// encodings.
+    Text {
+        kind: TextKind,
+        data: Vec<u8>,
+    },
+    TimeSignature(TimeSignature),
+    KeySignature(KeySignature),
+    EndOfTrack,
+    /// Any other meta event, with its type byte and data
+    Meta {
+        kind: u8,
+        data: Vec<u8>,
+    },
+    /// A status byte with no defined meaning in a file
+    Undefined(u8),
+}
+
+impl MidiEvent {
+    /// Builds a meta event from its type byte and data, decoding the known types
+    pub(crate) fn from_meta(kind: u8, data: Vec<u8>) -> Self {
+        if let Some(text_kind) = TextKind::from_meta(kind) {
+            return MidiEvent::Text {
+                kind: text_kind,
+                data,
+            };
+        }
+
+        match (kind, data.as_slice()) {
+            (0x58, &[numerator, denominator, clocks_per_click, notated_32nds_per_quarter]) => {
+                MidiEvent::TimeSignature(TimeSignature {
+                    numerator,
+                    denominator: 1u32.checked_shl(denominator as u32).unwrap_or(0),
+                    clocks_per_click,
+                    notated_32nds_per_quarter,
+                })
+            }
+            (0x59, &[sharps, minor]) => MidiEvent::KeySignature(KeySignature {
+                sharps: sharps as i8,
+                minor: minor != 0,
+            }),
+            _ => MidiEvent::Meta { kind, data },
+        }
+    }
+
+    /// The channel of channel events, or `None` for system and meta events
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            MidiEvent::NoteOff { channel, .. }
+            | MidiEvent::NoteOn { channel, .. }
+            | MidiEvent::PolyphonicAftertouch { channel, .. }
+            | MidiEvent::ControlChange { channel, .. }
+            | MidiEvent::ProgramChange { channel, .. }
+            | MidiEvent::ChannelAftertouch { channel, .. }
+            | MidiEvent::PitchBend { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
+}
+
+/// An event along with its position in the track
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackEvent {
+    /// Ticks since the previous event
+    pub delta: u32,
+    /// Ticks since the start of the track
+    pub tick: u64,
+    pub event: MidiEvent,
+}
@@ -0,0 +1,166 @@
+use crate::data::{KeyRange, Note};
+
+/// Selects a track by its index or by its name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackSelector {
+    Index(u32),
+    Name(String),
+}
+
+impl TrackSelector {
+    fn matches(&self, track: u32, name: Option<&str>) -> bool {
+        match self {
+            TrackSelector::Index(index) => *index == track,
+            TrackSelector::Name(selected) => name == Some(selected.as_str()),
+        }
+    }
+}
+
+/// Chooses which notes are kept while parsing. The default keeps every note.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoteFilter {
+    /// Only these tracks are parsed, unless it's empty
+    pub include_tracks: Vec<TrackSelector>,
+    /// Tracks that aren't parsed, even if they are included
+    pub exclude_tracks: Vec<TrackSelector>,
+    /// Bit `n` keeps notes on channel `n`, counting from 0, so percussion on
+    /// channel 10 is bit 9
+    pub channels: u16,
+    /// Keys to keep notes for, within the load options' key range
+    pub keys: KeyRange,
+    /// Notes quieter than this are dropped
+    pub min_velocity: u8,
+}
+
+impl Default for NoteFilter {
+    fn default() -> Self {
+        NoteFilter {
+            include_tracks: Vec::new(),
+            exclude_tracks: Vec::new(),
+            channels: 0xFFFF,
+            keys: KeyRange::EXTENDED,
+            min_velocity: 0,
+        }
+    }
+}
+
+impl NoteFilter {
+    pub fn keeps_track(&self, track: u32, name: Option<&str>) -> bool {
+        let included = self.include_tracks.is_empty()
+            || self.include_tracks.iter().any(|s| s.matches(track, name));
+        included && !self.exclude_tracks.iter().any(|s| s.matches(track, name))
+    }
+
+    pub fn keeps_channel(&self, channel: u8) -> bool {
+        self.channels & (1 << channel) != 0
+    }
+
+    pub fn keeps_note(&self, key: u8, note: &Note) -> bool {
+        self.keys.contains(key)
+            && self.keeps_channel(note.channel())
+            && note.velocity >= self.min_velocity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        midifile::{MIDILoadOptions, MIDIReaderMode},
+        tempo::Timeline,
+        testing::{parse, smf, TempFile, TrackBuilder},
+    };
+
+    /// Notes on channels 0, 1 and 9, on keys 60 to 62, at velocities 20 to 120
+    fn file() -> TempFile {
+        let melody = TrackBuilder::new()
+            .name(0, "Melody")
+            .note_on(0, 0, 60, 20)
+            .note_on(0, 1, 61, 70)
+            .note_on(0, 9, 62, 120)
+            .note_off(96, 0, 60)
+            .note_off(0, 1, 61)
+            .note_off(0, 9, 62)
+            .end(0);
+        let bass = TrackBuilder::new()
+            .name(0, "Bass")
+            .note_on(0, 0, 36, 100)
+            .note_off(96, 0, 36)
+            .end(0);
+        TempFile::new(&smf(96, &[melody, bass]))
+    }
+
+    /// The number of notes each track produced with `filter`
+    fn note_counts(filter: NoteFilter) -> Vec<u64> {
+        let (midi, _) = parse(
+            &file(),
+            MIDIReaderMode::Ram,
+            MIDILoadOptions::default(),
+            Timeline::Ticks,
+            &filter,
+        )
+        .unwrap();
+        let stats = midi.stats().as_ref().unwrap();
+        stats.tracks.iter().map(|t| t.note_count).collect()
+    }
+
+    #[test]
+    fn filters_notes() {
+        assert_eq!(note_counts(NoteFilter::default()), [3, 1]);
+        assert_eq!(
+            note_counts(NoteFilter {
+                channels: 1 << 9,
+                ..Default::default()
+            }),
+            [1, 0]
+        );
+        assert_eq!(
+            note_counts(NoteFilter {
+                channels: !(1 << 9),
+                ..Default::default()
+            }),
+            [2, 1]
+        );
+        assert_eq!(
+            note_counts(NoteFilter {
+                keys: KeyRange::new(61, 127),
+                ..Default::default()
+            }),
+            [2, 0]
+        );
+        assert_eq!(
+            note_counts(NoteFilter {
+                min_velocity: 70,
+                ..Default::default()
+            }),
+            [2, 1]
+        );
+        assert_eq!(
+            note_counts(NoteFilter {
+                channels: 0b11,
+                keys: KeyRange::new(0, 60),
+                min_velocity: 50,
+                ..Default::default()
+            }),
+            [0, 1]
+        );
+    }
+
+    #[test]
+    fn filters_tracks() {
+        assert_eq!(
+            note_counts(NoteFilter {
+                include_tracks: vec![TrackSelector::Name("Bass".to_string())],
+                ..Default::default()
+            }),
+            [0, 1]
+        );
+        assert_eq!(
+            note_counts(NoteFilter {
+                exclude_tracks: vec![TrackSelector::Index(1)],
+                ..Default::default()
+            }),
+            [3, 0]
+        );
+    }
+}
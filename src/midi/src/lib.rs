@@ -1,5 +1,14 @@
+pub mod cache;
 pub mod errors;
+pub mod events;
+pub mod filter;
+pub mod metadata;
 pub mod midifile;
 pub mod miditrack;
 pub mod data;
+pub mod tempo;
+pub mod stats;
+pub mod progress;
 mod readers;
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,155 @@
+use crate::events::{KeySignature, MidiEvent, TextKind, TimeSignature, TrackEvent};
+
+/// A metadata event with the tick and track it was found on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetaEntry<T> {
+    pub tick: u64,
+    pub track: u32,
+    pub value: T,
+}
+
+/// Text and musical meta events collected from every track. All lists are
+/// sorted by tick, with events on the same tick kept in track order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MidiMetadata {
+    /// The first name given to each track, indexed by track
+    pub track_names: Vec<Option<String>>,
+    pub copyright: Vec<MetaEntry<String>>,
+    pub texts: Vec<MetaEntry<String>>,
+    pub markers: Vec<MetaEntry<String>>,
+    pub lyrics: Vec<MetaEntry<String>>,
+    pub time_signatures: Vec<MetaEntry<TimeSignature>>,
+    pub key_signatures: Vec<MetaEntry<KeySignature>>,
+}
+
+/// Metadata collected from a single track, before it is merged with the
+/// other tracks'
+#[derive(Debug, Default)]
+pub(crate) struct TrackMetadata {
+    track: u32,
+    /// The first name given to the track
+    name: Option<String>,
+    /// Everything else, with `track_names` left empty
+    events: MidiMetadata,
+}
+
+impl TrackMetadata {
+    pub(crate) fn new(track: u32) -> Self {
+        TrackMetadata {
+            track,
+            ..Default::default()
+        }
+    }
+
+    /// Adds an event to the metadata if it is one that gets collected
+    pub(crate) fn record(&mut self, event: TrackEvent) {
+        let tick = event.tick;
+        let track = self.track;
+        let entry = |value| MetaEntry { tick, track, value };
+        let events = &mut self.events;
+
+        match event.event {
+            MidiEvent::Text { kind, data } => {
+                let text = String::from_utf8_lossy(&data).into_owned();
+                match kind {
+                    TextKind::TrackName => {
+                        if self.name.is_none() {
+                            self.name = Some(text);
+                        }
+                    }
+                    TextKind::Copyright => events.copyright.push(entry(text)),
+                    TextKind::Text => events.texts.push(entry(text)),
+                    TextKind::Marker => events.markers.push(entry(text)),
+                    TextKind::Lyric => events.lyrics.push(entry(text)),
+                    TextKind::InstrumentName | TextKind::CuePoint => {}
+                }
+            }
+            MidiEvent::TimeSignature(sig) => events.time_signatures.push(MetaEntry {
+                tick,
+                track,
+                value: sig,
+            }),
+            MidiEvent::KeySignature(sig) => events.key_signatures.push(MetaEntry {
+                tick,
+                track,
+                value: sig,
+            }),
+            _ => {}
+        }
+    }
+}
+
+impl MidiMetadata {
+    /// Combines the metadata of each track, given in track order
+    pub(crate) fn merge(tracks: Vec<TrackMetadata>, track_count: u32) -> MidiMetadata {
+        let mut merged = MidiMetadata {
+            track_names: vec![None; track_count as usize],
+            ..Default::default()
+        };
+
+        for track in tracks {
+            if let Some(name) = merged.track_names.get_mut(track.track as usize) {
+                *name = track.name;
+            }
+            let events = track.events;
+            merged.copyright.extend(events.copyright);
+            merged.texts.extend(events.texts);
+            merged.markers.extend(events.markers);
+            merged.lyrics.extend(events.lyrics);
+            merged.time_signatures.extend(events.time_signatures);
+            merged.key_signatures.extend(events.key_signatures);
+        }
+
+        // Stable sorts, so same-tick events stay in track order
+        merged.copyright.sort_by_key(|e| e.tick);
+        merged.texts.sort_by_key(|e| e.tick);
+        merged.markers.sort_by_key(|e| e.tick);
+        merged.lyrics.sort_by_key(|e| e.tick);
+        merged.time_signatures.sort_by_key(|e| e.tick);
+        merged.key_signatures.sort_by_key(|e| e.tick);
+
+        merged
+    }
+
+    /// The song title, taken from the name of the first track
+    pub fn title(&self) -> Option<&str> {
+        self.track_names.first()?.as_deref()
+    }
+
+    /// Ticks of each bar line before `end_tick`, following the time signature
+    /// changes. Files without a time signature are treated as 4/4. A time
+    /// signature change always starts a new bar.
+    pub fn bar_ticks(&self, ppq: u16, end_tick: u64) -> Vec<u64> {
+        let default = TimeSignature {
+            numerator: 4,
+            denominator: 4,
+            clocks_per_click: 24,
+            notated_32nds_per_quarter: 8,
+        };
+
+        let mut bars = Vec::new();
+        let mut tick = 0;
+        let mut sig = default;
+        let mut changes = self.time_signatures.iter().peekable();
+
+        while tick < end_tick {
+            while let Some(change) = changes.next_if(|c| c.tick <= tick) {
+                // Signatures that would give empty bars are ignored
+                if change.value.numerator != 0 && change.value.denominator != 0 {
+                    sig = change.value;
+                }
+            }
+
+            let bar_len = ppq as u64 * 4 * sig.numerator as u64 / sig.denominator as u64;
+
+            bars.push(tick);
+            tick += bar_len.max(1);
+
+            if let Some(next) = changes.peek() {
+                tick = tick.min(next.tick);
+            }
+        }
+
+        bars
+    }
+}
@@ -1,17 +1,278 @@
-use std::{collections::VecDeque, rc::Rc};
+use std::{collections::HashMap, mem};
 
 use getset::Getters;
+use log::{debug, trace, warn};
+use rayon::prelude::*;
 use to_vec::ToVec;
 
 use crate::{
-    data::{IntVector4, TreeSerializer},
-    errors::MIDILoadError,
-    miditrack::{MIDITrack, MidiTrackOutput},
-    readers::{DiskReader, MIDIReader, RAMReader},
+    data::{
+        segment_time, IntVector4, KeyRange, Leaf, Note, NoteArena, TreeSerializer, MAX_SEGMENTS,
+        MAX_SEGMENT_LENGTH, ROOTS_PER_SEGMENT,
+    },
+    errors::{LoadWarning, MIDILoadError},
+    filter::NoteFilter,
+    metadata::MidiMetadata,
+    miditrack::{MIDITrack, MidiTrackOutput, NoteId, OverlapPolicy, TrackEvents},
+    readers::{DiskReader, MIDIReader, MmapReader, RAMReader, TrackReader},
+    progress::{CancelToken, LoadPhase, PhaseProgress, ProgressSink, TrackProgress},
+    stats::{merged_polyphony_peak, MidiStats},
+    tempo::{TempoMap, TimeDivision, Timeline},
 };
+/// Selects how the file's bytes are accessed while parsing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MIDIReaderMode {
+    /// Stream each track from disk through a small buffer
+    Disk,
+    /// Read the whole file into memory up front
+    Ram,
+    /// Memory-map the file and let the OS page tracks in on demand
+    Mmap,
+}
+
+/// The file layout declared in the `MThd` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MIDIFormat {
+    /// Format 0, a single multi-channel track
+    SingleTrack,
+    /// Format 1, simultaneous tracks sharing one timeline
+    MultiTrack,
+    /// Format 2, independent single-track sequences
+    MultiSequence,
+}
+
+impl MIDIFormat {
+    fn from_header(format: u16) -> Option<Self> {
+        match format {
+            0 => Some(MIDIFormat::SingleTrack),
+            1 => Some(MIDIFormat::MultiTrack),
+            2 => Some(MIDIFormat::MultiSequence),
+            _ => None,
+        }
+    }
+}
+
+/// Default for `MIDILoadOptions::memory_budget`
+pub const DEFAULT_MEMORY_BUDGET: usize = 256 << 20;
+
+/// Default for `MIDILoadOptions::segment_seconds`
+pub const DEFAULT_SEGMENT_SECONDS: f64 = 10.0;
+
+/// Options controlling how a file is loaded
+#[derive(Debug, Clone)]
+pub struct MIDILoadOptions {
+    /// Repair common corruption instead of failing: wrong track lengths are
+    /// corrected by resynchronising on the next `MTrk` header, and data that
+    /// isn't a chunk is skipped. Each repair is recorded as a warning.
+    pub lenient: bool,
+    /// How overlapping notes on the same key and channel are paired up
+    pub overlap_policy: OverlapPolicy,
+    /// Keys to keep notes for. Notes on other keys are dropped while parsing,
+    /// and renderers show this range by default.
+    pub key_range: KeyRange,
+    /// Checked while loading and parsing, to abort the load early
+    pub cancel: CancelToken,
+    /// Roughly how many bytes of parsed notes can wait to be added to the
+    /// trees. Tracks are read in rounds that stop once they hold this much.
+    pub memory_budget: usize,
+    /// How much of the song each time segment of the trees covers, in seconds.
+    /// Renderers keep only the segments around the view on the GPU, so shorter
+    /// segments take fewer bytes each, at the cost of repeating the notes that
+    /// play across several. On a tick timeline, segments are as many ticks as
+    /// the song's first `segment_seconds`. Segments are never longer than
+    /// `MAX_SEGMENT_LENGTH`, nor shorter than one unit of the timeline. Must be
+    /// a positive, normal number.
+    pub segment_seconds: f64,
+}
+
+impl Default for MIDILoadOptions {
+    fn default() -> Self {
+        MIDILoadOptions {
+            lenient: false,
+            overlap_policy: OverlapPolicy::default(),
+            key_range: KeyRange::default(),
+            cancel: CancelToken::default(),
+            memory_budget: DEFAULT_MEMORY_BUDGET,
+            segment_seconds: DEFAULT_SEGMENT_SECONDS,
+        }
+    }
+}
+
+/// A chunk with an unknown id, skipped while loading
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlienChunk {
+    pub id: String,
+    /// Offset of the chunk header
+    pub offset: u64,
+    pub len: u64,
+}
+
+fn is_chunk_id(id: &[u8; 4]) -> bool {
+    id.iter().all(|b| (0x20..0x7F).contains(b))
+}
+
 struct TrackPos {
     pos: u64,
-    len: u32,
+    len: u64,
+}
+
+/// A track being parsed, holding the notes it has read until they are added
+/// to the trees
+struct TrackParser<'a, 'b> {
+    track_id: u32,
+    track: MIDITrack,
+    output: MidiTrackOutput,
+    progress: TrackProgress<'a, 'b>,
+    /// How many notes the track can hold before it stops reading
+    budget: u64,
+    /// Tick of the next unread event, or `None` once the track has ended
+    next_tick: Option<u64>,
+}
+
+impl<'a, 'b> TrackParser<'a, 'b> {
+    /// Reads at least one tick, then keeps reading until the track holds its
+    /// budget of notes or ends
+    fn read_notes(
+        &mut self,
+        timeline: Timeline,
+        tempo_map: &TempoMap,
+    ) -> Result<(), MIDILoadError> {
+        let mut first = true;
+        while let Some(tick) = self.track.next_event_tick(&mut self.output)? {
+            self.next_tick = Some(tick);
+            if !first && self.output.note_count() >= self.budget {
+                return Ok(());
+            }
+            first = false;
+
+            self.progress.update(self.track.position())?;
+
+            let time_int = timeline.time_at_tick(tempo_map, tick);
+
+            self.track.read_tick(&mut self.output, time_int)?;
+        }
+        self.next_tick = None;
+        Ok(())
+    }
+
+    fn finished(&self) -> bool {
+        self.next_tick.is_none() && self.output.note_count() == 0
+    }
+}
+
+/// Builds a key's trees for every segment from notes given in order of their
+/// start times. Only segments with notes playing in them get a tree, so long
+/// gaps between notes take no memory.
+struct KeyTrees {
+    notes: NoteArena,
+    /// Trees of the segments with notes, by segment
+    segments: Vec<(usize, TreeSerializer)>,
+    /// Number of segments started so far, with or without a tree
+    segment_count: usize,
+    segment_length: i64,
+    /// Notes that may still be playing at the start of the next segment
+    playing: Vec<usize>,
+    /// Notes given to the trees before they ended, by track and id
+    unended: HashMap<(u32, NoteId), usize>,
+    /// Latest end of the key's notes
+    end: i64,
+}
+
+impl KeyTrees {
+    fn new(segment_length: i64) -> Self {
+        KeyTrees {
+            notes: NoteArena::new(),
+            segments: Vec::new(),
+            segment_count: 0,
+            segment_length,
+            playing: Vec::new(),
+            unended: HashMap::new(),
+            end: 0,
+        }
+    }
+
+    fn end_note(&mut self, track: u32, id: NoteId, time: i64) {
+        if let Some(index) = self.unended.remove(&(track, id)) {
+            self.notes.end_note(index, time);
+            self.end = self.end.max(time);
+        }
+    }
+
+    fn feed_note(&mut self, track: u32, id: NoteId, note: Note) {
+        let segment = segment_time(note.start, self.segment_length).0 as usize;
+        self.extend_segments(segment + 1);
+
+        let unended = note.unended();
+        if !unended {
+            self.end = self.end.max(note.end);
+        }
+        let index = self.notes.push(note);
+        if unended {
+            self.unended.insert((track, id), index);
+        }
+
+        // Nothing was playing at the start of the segment if it has no tree
+        if self.segments.last().map(|s| s.0) != Some(segment) {
+            let tree = TreeSerializer::new_from(segment as i64 * self.segment_length, 4);
+            self.segments.push((segment, tree));
+        }
+        let (_, tree) = self.segments.last_mut().unwrap();
+        tree.feed_note(&self.notes, index);
+        self.playing.push(index);
+    }
+
+    /// Starts segments until there are `count`, giving each one that starts
+    /// with notes still playing a tree holding them
+    fn extend_segments(&mut self, count: usize) {
+        while self.segment_count < count {
+            let segment = self.segment_count;
+            let origin = segment as i64 * self.segment_length;
+            self.prune_playing(origin);
+            if self.playing.is_empty() {
+                // Nothing plays until the next note is given
+                self.segment_count = count;
+                break;
+            }
+
+            let mut tree = TreeSerializer::new_from(origin, 4);
+            for &note in &self.playing {
+                tree.feed_note(&self.notes, note);
+            }
+            self.segments.push((segment, tree));
+            self.segment_count += 1;
+        }
+    }
+
+    /// Forgets the notes that ended by `time`
+    fn prune_playing(&mut self, time: i64) {
+        let notes = &self.notes;
+        self.playing.retain(|&n| {
+            let note = notes.get(n);
+            note.unended() || note.end > time
+        });
+    }
+
+    /// Finishes the trees of the first `segment_count` segments, returning
+    /// them by segment along with the notes they refer to
+    fn complete(mut self, segment_count: usize) -> (NoteArena, Vec<(usize, Leaf)>) {
+        self.extend_segments(segment_count);
+        debug_assert!(self.unended.is_empty());
+
+        let notes = self.notes;
+        let trees = self
+            .segments
+            .into_iter()
+            .map(|(segment, tree)| (segment, tree.complete(&notes)))
+            .to_vec();
+        (notes, trees)
+    }
+}
+
+/// The tree of a segment from those `KeyTrees::complete` returned, or `None`
+/// if nothing plays in it
+fn segment_tree(trees: &[(usize, Leaf)], segment: usize) -> Option<&Leaf> {
+    let i = trees.binary_search_by_key(&segment, |t| t.0).ok()?;
+    Some(&trees[i].1)
 }
 
 #[derive(Getters)]
@@ -20,164 +281,995 @@ pub struct MIDIFile {
     track_positions: Vec<TrackPos>,
 
     #[getset(get = "pub")]
-    ppq: u16,
+    format: MIDIFormat,
+    #[getset(get = "pub")]
+    division: TimeDivision,
     #[getset(get = "pub")]
     track_count: u32,
+
+    /// Chunks that weren't tracks, in file order
+    #[getset(get = "pub")]
+    alien_chunks: Vec<AlienChunk>,
+
+    /// Tempo map of the last `parse_all_tracks` call
+    #[getset(get = "pub")]
+    tempo_map: Option<TempoMap>,
+
+    /// Metadata collected by the last `parse_all_tracks` call
+    #[getset(get = "pub")]
+    metadata: Option<MidiMetadata>,
+
+    /// Statistics of the last `parse_all_tracks` call
+    #[getset(get = "pub")]
+    stats: Option<MidiStats>,
+
+    /// Number of time segments in the trees of the last `parse_all_tracks`
+    /// call, each starting with `ROOTS_PER_SEGMENT` roots
+    #[getset(get = "pub")]
+    segment_count: u32,
+
+    /// Length of the time segments of the last `parse_all_tracks` call, on
+    /// its timeline
+    #[getset(get = "pub")]
+    segment_length: i64,
+
+    /// Timeline of the last `parse_all_tracks` call
+    #[getset(get = "pub")]
+    timeline: Option<Timeline>,
+
+    #[getset(get = "pub")]
+    options: MIDILoadOptions,
+
+    /// Problems worked around while loading and parsing
+    #[getset(get = "pub")]
+    warnings: Vec<LoadWarning>,
 }
 
 impl MIDIFile {
     pub fn new(
         filename: &str,
-        load_to_ram: bool,
-        read_progress: Option<&dyn Fn(u32)>,
+        reader_mode: MIDIReaderMode,
+        progress: Option<&dyn ProgressSink>,
+    ) -> Result<Self, MIDILoadError> {
+        MIDIFile::new_with_options(filename, reader_mode, MIDILoadOptions::default(), progress)
+    }
+
+    pub fn new_with_options(
+        filename: &str,
+        reader_mode: MIDIReaderMode,
+        options: MIDILoadOptions,
+        progress: Option<&dyn ProgressSink>,
     ) -> Result<Self, MIDILoadError> {
-        let mut reader = match load_to_ram {
-            true => Box::new(RAMReader::new(filename)?) as Box<dyn MIDIReader>,
-            false => Box::new(DiskReader::new(filename)?) as Box<dyn MIDIReader>,
+        let seconds = options.segment_seconds;
+        if !seconds.is_normal() || seconds < 0.0 {
+            return Err(MIDILoadError::InvalidSegmentSeconds { seconds });
+        }
+
+        let mut reader = match reader_mode {
+            MIDIReaderMode::Ram => Box::new(RAMReader::new(filename)?) as Box<dyn MIDIReader>,
+            MIDIReaderMode::Disk => Box::new(DiskReader::new(filename)?) as Box<dyn MIDIReader>,
+            MIDIReaderMode::Mmap => Box::new(MmapReader::new(filename)?) as Box<dyn MIDIReader>,
         };
 
+        let (smf_start, smf_end) = MIDIFile::locate_smf(reader.as_mut())?;
+        reader.seek(smf_start)?;
+
         reader.assert_header("MThd")?;
 
         let header_len = reader.read_value(4)?;
 
         if header_len != 6 {
-            return Err(MIDILoadError::CorruptChunks);
+            return Err(MIDILoadError::InvalidHeaderLength {
+                length: header_len,
+                offset: smf_start + 4,
+            });
         }
 
-        let _format = reader.read_value(2)?;
-        let _track_count_bad = reader.read_value(2)?;
-        let ppq = reader.read_value(2)? as u16;
+        let format_offset = reader.get_position()?;
+        let format = reader.read_value(2)? as u16;
+        let declared_track_count = reader.read_value(2)?;
+        let division_offset = reader.get_position()?;
+        let division = TimeDivision::from_header(reader.read_value(2)? as u16);
+        if !division.is_valid() {
+            return Err(MIDILoadError::InvalidDivision {
+                offset: division_offset,
+            });
+        }
+
+        let mut warnings = Vec::new();
+
+        let format = match MIDIFormat::from_header(format) {
+            Some(MIDIFormat::MultiSequence) => return Err(MIDILoadError::Format2MIDI),
+            Some(format) => format,
+            None if options.lenient => {
+                warnings.push(LoadWarning::UnknownFormat { format });
+                MIDIFormat::MultiTrack
+            }
+            None => {
+                return Err(MIDILoadError::UnknownFormat {
+                    format,
+                    offset: format_offset,
+                })
+            }
+        };
 
         let mut track_count = 0 as u32;
         let mut track_positions = Vec::<TrackPos>::new();
-        while !reader.is_end()? {
-            reader.assert_header("MTrk")?;
-            track_count += 1;
-            let len = reader.read_value(4)?;
+        let mut alien_chunks = Vec::new();
+        let progress = PhaseProgress::new(progress, LoadPhase::DiscoveringChunks, smf_end);
+        while reader.get_position()? < smf_end {
+            let chunk_start = reader.get_position()?;
+            progress.set(chunk_start);
+            options.cancel.check()?;
+
+            if smf_end - chunk_start < 8 {
+                if !options.lenient {
+                    return Err(MIDILoadError::UnexpectedEnd { offset: smf_end });
+                }
+                warnings.push(LoadWarning::SkippedData {
+                    offset: chunk_start,
+                    len: smf_end - chunk_start,
+                });
+                break;
+            }
+
+            let id = reader.read_value(4)?.to_be_bytes();
+            let declared_len = reader.read_value(4)?;
             let pos = reader.get_position()?;
+
+            let is_track = &id == b"MTrk";
+            let is_valid_chunk =
+                is_track || (is_chunk_id(&id) && pos + declared_len as u64 <= smf_end);
+
+            if !is_valid_chunk {
+                if options.lenient {
+                    // Resynchronise on the next track header
+                    let skip_to = reader
+                        .find(b"MTrk", chunk_start + 1)?
+                        .filter(|&next| next < smf_end)
+                        .unwrap_or(smf_end);
+                    warnings.push(LoadWarning::SkippedData {
+                        offset: chunk_start,
+                        len: skip_to - chunk_start,
+                    });
+                    reader.seek(skip_to)?;
+                    continue;
+                } else if is_chunk_id(&id) {
+                    return Err(MIDILoadError::ChunkOutOfBounds {
+                        offset: pos,
+                        len: declared_len as u64,
+                        file_len: smf_end,
+                    });
+                } else {
+                    return Err(MIDILoadError::UnexpectedChunk {
+                        expected: "MTrk".to_string(),
+                        found: String::from_utf8_lossy(&id).into_owned(),
+                        offset: chunk_start,
+                    });
+                }
+            }
+
+            if !is_track {
+                // The spec requires unknown chunk types to be skipped
+                alien_chunks.push(AlienChunk {
+                    id: String::from_utf8_lossy(&id).into_owned(),
+                    offset: chunk_start,
+                    len: declared_len as u64,
+                });
+                reader.seek(pos + declared_len as u64)?;
+                continue;
+            }
+
+            let mut len = declared_len as u64;
+            if !options.lenient && pos + len > smf_end {
+                return Err(MIDILoadError::UnexpectedEnd { offset: smf_end });
+            }
+            if options.lenient && !MIDIFile::is_chunk_end(reader.as_mut(), pos + len, smf_end)? {
+                // Without a following track, keep the declared length unless it
+                // runs past the end, and let anything after it be skipped
+                len = match reader.find(b"MTrk", pos)?.filter(|&next| next < smf_end) {
+                    Some(next) => next - pos,
+                    None => len.min(smf_end - pos),
+                };
+                if len != declared_len as u64 {
+                    warnings.push(LoadWarning::ChunkLengthMismatch {
+                        track: track_count,
+                        offset: chunk_start,
+                        declared: declared_len,
+                        actual: len,
+                    });
+                }
+            }
+
+            track_count += 1;
             track_positions.push(TrackPos { len, pos });
-            reader.skip(len as u64)?;
+            reader.seek(pos + len)?;
+        }
+        progress.set(smf_end);
 
-            match read_progress {
-                Some(progress) => progress(track_count),
-                _ => {}
-            };
+        if track_count != declared_track_count {
+            warnings.push(LoadWarning::TrackCountMismatch {
+                declared: declared_track_count,
+                found: track_count,
+            });
+        }
+
+        debug!(
+            "Found {} tracks and {} other chunks, {:?} with {:?}",
+            track_count,
+            alien_chunks.len(),
+            format,
+            division
+        );
+        for warning in &warnings {
+            warn!("{}", warning);
         }
 
         Ok(MIDIFile {
             reader,
-            ppq,
+            format,
+            division,
             track_count,
             track_positions,
+            alien_chunks,
+            tempo_map: None,
+            metadata: None,
+            stats: None,
+            segment_count: 0,
+            segment_length: 0,
+            timeline: None,
+            options,
+            warnings,
         })
     }
 
-    pub fn parse_all_tracks(&mut self, tps: u32) -> Result<Vec<IntVector4>, MIDILoadError> {
-        let mut tracks = self
-            .track_positions
-            .iter()
+    /// Finds the standard MIDI data in the file, unwrapping RIFF `RMID` files.
+    /// Returns the start and end offsets of the data.
+    fn locate_smf(reader: &mut dyn MIDIReader) -> Result<(u64, u64), MIDILoadError> {
+        if reader.length() < 12 || reader.read_value(4)?.to_be_bytes() != *b"RIFF" {
+            return Ok((0, reader.length()));
+        }
+
+        // RIFF sizes are little endian
+        reader.read_value(4)?;
+        reader.assert_header("RMID")?;
+
+        loop {
+            let id = reader.read_value(4)?.to_be_bytes();
+            let size = reader.read_value(4)?.swap_bytes() as u64;
+            let data = reader.get_position()?;
+
+            if &id == b"data" {
+                return Ok((data, (data + size).min(reader.length())));
+            }
+
+            // Chunks are padded to an even size
+            reader.seek(data + size + (size & 1))?;
+        }
+    }
+
+    /// Whether a chunk ending at `end` is followed by another chunk or by the
+    /// end of the MIDI data
+    fn is_chunk_end(
+        reader: &mut dyn MIDIReader,
+        end: u64,
+        smf_end: u64,
+    ) -> Result<bool, MIDILoadError> {
+        if end == smf_end {
+            return Ok(true);
+        }
+        if end + 8 > smf_end {
+            return Ok(false);
+        }
+
+        let pos = reader.get_position()?;
+        reader.seek(end)?;
+        let id = reader.read_value(4)?.to_be_bytes();
+        reader.seek(pos)?;
+        Ok(is_chunk_id(&id))
+    }
+
+    /// Ticks per quarter note, or `None` for SMPTE timed files
+    pub fn ppq(&self) -> Option<u16> {
+        match self.division {
+            TimeDivision::Ppq(ppq) => Some(ppq),
+            TimeDivision::Smpte { .. } => None,
+        }
+    }
+
+    fn open_track_readers(&self) -> Result<Vec<Box<dyn TrackReader>>, MIDILoadError> {
+        let mut readers = Vec::with_capacity(self.track_positions.len());
+        for (i, pos) in self.track_positions.iter().enumerate() {
+            let reader = self
+                .reader
+                .open_reader(pos.pos, pos.len, false)
+                .map_err(|e| e.in_track(i as u32, pos.pos))?;
+            readers.push(reader);
+        }
+        Ok(readers)
+    }
+
+    /// Total length of all tracks' data, in bytes
+    fn track_bytes(&self) -> u64 {
+        self.track_positions.iter().map(|p| p.len).sum()
+    }
+
+    /// Opens a single track for reading its raw events
+    pub fn track_events(&self, track: u32) -> Result<TrackEvents, MIDILoadError> {
+        let pos = match self.track_positions.get(track as usize) {
+            Some(pos) => pos,
+            None => return Err(MIDILoadError::TrackNotFound { track }),
+        };
+        let reader = self
+            .reader
+            .open_reader(pos.pos, pos.len, false)
+            .map_err(|e| e.in_track(track, pos.pos))?;
+        Ok(MIDITrack::new(reader, track).into_events())
+    }
+
+    /// Runs the pre-pass over every track, collecting the tempo map, metadata
+    /// and statistics. Tempo changes on the same tick keep their track order,
+    /// so the last one read wins.
+    fn read_global_events(
+        &self,
+        progress: Option<&dyn ProgressSink>,
+    ) -> Result<(TempoMap, MidiMetadata, MidiStats), MIDILoadError> {
+        let overlap_policy = self.options.overlap_policy;
+        let cancel = &self.options.cancel;
+        let positions = &self.track_positions;
+        let progress = PhaseProgress::new(progress, LoadPhase::TempoPass, self.track_bytes());
+
+        let per_track = self
+            .open_track_readers()?
+            .into_par_iter()
             .enumerate()
-            .map(|(i, pos)| {
-                let r = self.reader.open_reader(pos.pos, pos.len as u64, true);
-                MIDITrack::new(r, i as u32)
+            .map(|(i, r)| {
+                let pos = &positions[i];
+                let mut track = MIDITrack::new(r, i as u32);
+                track.set_overlap_policy(overlap_policy);
+                track.read_global_events(TrackProgress::new(&progress, cancel, pos.pos, pos.len))
             })
-            .to_vec();
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut changes = Vec::new();
+        let mut metadata = Vec::new();
+        let mut scans = Vec::new();
+        for (track_changes, track_metadata, scan) in per_track {
+            changes.extend(track_changes);
+            metadata.push(track_metadata);
+            scans.push(scan);
+        }
+        changes.sort_by_key(|c| c.0);
+
+        let tempo_map = TempoMap::new(self.division, &changes);
+        let metadata = MidiMetadata::merge(metadata, self.track_count);
+        let stats = MidiStats::merge(scans, &tempo_map);
+
+        debug!(
+            "Pre-pass read {} events with {} tempo changes",
+            stats.event_count,
+            changes.len()
+        );
+
+        Ok((tempo_map, metadata, stats))
+    }
+
+    pub fn read_tempo_map(&self) -> Result<TempoMap, MIDILoadError> {
+        Ok(self.read_global_events(None)?.0)
+    }
+
+    /// Reads the file's metadata without parsing any notes
+    pub fn read_metadata(&self) -> Result<MidiMetadata, MIDILoadError> {
+        Ok(self.read_global_events(None)?.1)
+    }
 
-        let mut time = 0.0;
+    /// Counts notes, events and polyphony without parsing any notes
+    pub fn scan_stats(&self) -> Result<MidiStats, MIDILoadError> {
+        let mut stats = self.read_global_events(None)?.2;
+        stats.polyphony_peak = Some(self.scan_polyphony()?);
+        Ok(stats)
+    }
 
-        let mut output = MidiTrackOutput::new(self.ppq as u32);
+    /// Measures the most notes sounding at once across all tracks by reading
+    /// them side by side, which takes a second pass over the file
+    fn scan_polyphony(&self) -> Result<u32, MIDILoadError> {
+        let cancel = &self.options.cancel;
+        let tracks = self
+            .open_track_readers()?
+            .into_iter()
+            .enumerate()
+            .map(|(i, r)| {
+                let mut track = MIDITrack::new(r, i as u32);
+                track.set_overlap_policy(self.options.overlap_policy);
+                track
+                    .into_polyphony_changes()
+                    .map(move |change| cancel.check().and(change))
+            })
+            .to_vec();
+        merged_polyphony_peak(tracks)
+    }
 
-        let mut trees = Vec::new();
-        let mut vecs = Vec::new();
+    /// Parses every track into the serialized trees, keeping only the notes
+    /// that pass `filter`. Tracks the filter excludes are skipped entirely.
+    /// Returns a block per time segment, each starting with
+    /// `ROOTS_PER_SEGMENT` roots and indexed from its own start.
+    pub fn parse_all_tracks(
+        &mut self,
+        timeline: Timeline,
+        filter: &NoteFilter,
+        progress: Option<&dyn ProgressSink>,
+    ) -> Result<Vec<Vec<IntVector4>>, MIDILoadError> {
+        let (tempo_map, metadata, mut stats) = self.read_global_events(progress)?;
+        stats.polyphony_peak = Some(self.scan_polyphony()?);
+        let options = &self.options;
+        let cancel = &self.options.cancel;
+        let positions = &self.track_positions;
 
-        for _ in 0..256 {
-            trees.push(TreeSerializer::new(4));
-            vecs.push(VecDeque::new());
+        // The timeline is split into segments, each with its own tree per key,
+        // so that renderers can upload the song piece by piece and times within
+        // the trees fit in 32 bits. No note ends after the last event, so the
+        // number of segments is known before any are made.
+        let segment_length = timeline
+            .time_at_seconds(&tempo_map, options.segment_seconds)
+            .clamp(1, MAX_SEGMENT_LENGTH);
+        let last_event = timeline.time_at_tick(&tempo_map, stats.length_ticks);
+        let segment_count = segment_time(last_event, segment_length).0 + 1;
+        if segment_count > MAX_SEGMENTS {
+            return Err(MIDILoadError::MIDITooLong);
         }
 
-        let mut all_ended = false;
-        while !all_ended {
-            let time_int = (time * tps as f64) as i64;
-            if time_int > i32::MAX as i64 {
-                return Err(MIDILoadError::MIDITooLong);
+        let parse_progress = PhaseProgress::new(progress, LoadPhase::Parsing, self.track_bytes());
+        let mut tracks = Vec::new();
+        for (i, reader) in self.open_track_readers()?.into_iter().enumerate() {
+            let pos = &positions[i];
+            let progress = TrackProgress::new(&parse_progress, cancel, pos.pos, pos.len);
+            let name = metadata.track_names.get(i).and_then(|n| n.as_deref());
+            if !filter.keeps_track(i as u32, name) {
+                progress.finish();
+                continue;
             }
-            let time_int = time_int as i32;
 
-            all_ended = true;
-            for track in tracks.iter_mut() {
-                if track.ended() {
-                    continue;
+            let mut track = MIDITrack::new(reader, i as u32);
+            track.set_overlap_policy(options.overlap_policy);
+            tracks.push(TrackParser {
+                track_id: i as u32,
+                track,
+                output: MidiTrackOutput::new(),
+                progress,
+                budget: 0,
+                next_tick: None,
+            });
+        }
+
+        // The budget is shared out by how many notes each track has, so that
+        // tracks tend to read up to the same point in each round
+        let budget = (options.memory_budget / mem::size_of::<Note>()).max(1) as u64;
+        let track_notes = tracks
+            .iter()
+            .map(|t| stats.tracks[t.track_id as usize].note_count)
+            .sum::<u64>()
+            .max(1);
+        for t in &mut tracks {
+            let notes = stats.tracks[t.track_id as usize].note_count;
+            t.budget = (budget as u128 * notes as u128 / track_notes as u128).max(1) as u64;
+        }
+
+        // Tracks are read in rounds. After each round, the notes starting
+        // before the point every track has reached are added to the trees,
+        // including notes that haven't ended yet. The rest wait for a later
+        // round, along with the ends of the notes that were added unended.
+        let mut keys = (0..256).map(|_| KeyTrees::new(segment_length)).to_vec();
+        let mut note_counts = vec![0; self.track_count as usize];
+        let mut track_warnings = vec![None; self.track_count as usize];
+        let mut flushed = Vec::new();
+        while !tracks.is_empty() {
+            cancel.check()?;
+
+            tracks
+                .par_iter_mut()
+                .map(|t| {
+                    t.read_notes(timeline, &tempo_map)
+                        .map_err(|e| e.in_track(t.track_id, t.track.position()))
+                })
+                .collect::<Result<(), _>>()?;
+
+            let flush_time = tracks
+                .iter()
+                .filter_map(|t| t.next_tick)
+                .map(|tick| timeline.time_at_tick(&tempo_map, tick))
+                .min()
+                .unwrap_or(i64::MAX);
+
+            let mut ends = (0..256).map(|_| Vec::new()).to_vec();
+            let mut batches = (0..256).map(|_| Vec::new()).to_vec();
+            for t in &mut tracks {
+                let track_id = t.track_id;
+                for key in 0..256 {
+                    let late_ends = t.output.take_late_ends(key as i32).into_iter();
+                    ends[key].extend(late_ends.map(|(id, time)| (track_id, id, time)));
+
+                    t.output.flush_notes(key as i32, flush_time, &mut flushed);
+                    let keep_key = options.key_range.contains(key as u8);
+                    for (id, note) in flushed.drain(..) {
+                        if !keep_key || !filter.keeps_note(key as u8, &note) {
+                            continue;
+                        }
+                        note_counts[track_id as usize] += 1;
+                        batches[key].push((track_id, id, note));
+                    }
                 }
-                all_ended = false;
-                track.read_tick(&mut output, time_int)?;
             }
+            trace!(
+                "Adding {} notes to the trees",
+                batches.iter().map(|b| b.len()).sum::<usize>()
+            );
 
-            time += output.last_tempo_time_step();
-
-            if *output.note_events_counted() > 10000000 {
-                println!("Feeding notes, {}", output.note_count());
-
-                for i in 0..256 {
-                    let vec = &mut vecs[i];
-                    let tree = &mut trees[i];
-                    output.flush_notes(i as i32, vec);
-                    loop {
-                        match vec.pop_back() {
-                            None => break,
-                            Some(note) => {
-                                tree.feed_note(Rc::new(note));
-                            }
-                        }
+            keys.par_iter_mut()
+                .zip(ends)
+                .zip(batches)
+                .for_each(|((trees, ends), mut batch)| {
+                    for (track, id, time) in ends {
+                        trees.end_note(track, id, time);
+                    }
+                    // Stable, so that notes starting together keep track order
+                    batch.sort_by_key(|n| n.2.start);
+                    for (track, id, note) in batch {
+                        trees.feed_note(track, id, note);
                     }
+                    trees.prune_playing(trees.segment_count as i64 * segment_length);
+                });
+
+            let (finished, running): (Vec<_>, Vec<_>) =
+                tracks.into_iter().partition(|t| t.finished());
+            tracks = running;
+            for t in finished {
+                t.output.assert_empty();
+                t.progress.finish();
+                let id = t.track_id as usize;
+                trace!("Track {} produced {} notes", id, note_counts[id]);
+                if !t.track.has_end_event() {
+                    let track = t.track_id;
+                    track_warnings[id] = Some(LoadWarning::MissingEndOfTrack { track });
                 }
+            }
+        }
+
+        for warning in track_warnings.into_iter().flatten() {
+            warn!("{}", warning);
+            self.warnings.push(warning);
+        }
+
+        debug_assert!(keys.iter().all(|k| k.end <= last_event));
+        let segment_count = segment_count as usize;
+
+        let tree_progress = PhaseProgress::new(progress, LoadPhase::BuildingTrees, 256);
+        let trees = keys
+            .into_par_iter()
+            .map(|key| {
+                cancel.check()?;
+                let trees = key.complete(segment_count);
+                tree_progress.add(1);
+                Ok(trees)
+            })
+            .collect::<Result<Vec<_>, MIDILoadError>>()?;
+        let key_note_counts = trees.iter().map(|(notes, _)| notes.len()).to_vec();
+
+        // Segments without a tree are serialized as a single empty leaf
+        stats.set_note_counts(&note_counts);
+        let node_counts = trees
+            .iter()
+            .map(|(_, segments)| {
+                let empty = (segment_count - segments.len()) as u64;
+                segments.iter().map(|(_, l)| l.count()).sum::<u64>() + empty
+            })
+            .to_vec();
+        stats.tree_nodes = Some(node_counts.iter().sum());
 
-                output.reset_note_event_counted();
+        for (key, (count, notes)) in node_counts.iter().zip(&key_note_counts).enumerate() {
+            if *notes > 0 {
+                trace!("Key {} has {} notes in {} nodes", key, notes, count);
             }
         }
-                
-        for i in 0..256 {
-            let vec = &mut vecs[i];
-            let tree = &mut trees[i];
-            output.flush_notes(i as i32, vec);
-            loop {
-                match vec.pop_back() {
-                    None => break,
-                    Some(note) => {
-                        tree.feed_note(Rc::new(note));
-                    }
+        debug!(
+            "Built trees for {} notes with {} nodes in {} segments",
+            stats.note_count,
+            stats.tree_nodes.unwrap_or(0),
+            segment_count
+        );
+
+        // Each segment is serialized into its own block, with indices relative
+        // to the start of the block, so segments can be uploaded on their own
+        let empty = Leaf::Note(None);
+        let serialized = (0..segment_count)
+            .into_par_iter()
+            .map(|i| {
+                let key_trees = trees
+                    .iter()
+                    .map(|(notes, segments)| (notes, segment_tree(segments, i).unwrap_or(&empty)))
+                    .to_vec();
+
+                // Indices within a block are 32-bit
+                let nodes = key_trees.iter().map(|(_, tree)| tree.count()).sum::<u64>();
+                if ROOTS_PER_SEGMENT as u64 + nodes > i32::MAX as u64 {
+                    return Err(MIDILoadError::MIDITooLong);
                 }
-            }
+
+                let base = i as i64 * segment_length;
+                let mut block = (0..ROOTS_PER_SEGMENT)
+                    .map(|_| IntVector4::default())
+                    .to_vec();
+                for (key, (notes, tree)) in key_trees.into_iter().enumerate() {
+                    block[key].val1 = tree.serialize_to_vec(&mut block, base, notes);
+                }
+                Ok(block)
+            })
+            .collect::<Result<Vec<_>, MIDILoadError>>()?;
+
+        self.tempo_map = Some(tempo_map);
+        self.metadata = Some(metadata);
+        self.stats = Some(stats);
+        self.segment_count = segment_count as u32;
+        self.segment_length = segment_length;
+        self.timeline = Some(timeline);
+
+        Ok(serialized)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{chunk, header, note_at, parse, smf, tree_values, TempFile, TrackBuilder};
+
+    fn lenient() -> MIDILoadOptions {
+        MIDILoadOptions {
+            lenient: true,
+            ..Default::default()
+        }
+    }
+
+    fn open(bytes: &[u8], options: MIDILoadOptions) -> Result<MIDIFile, MIDILoadError> {
+        let file = TempFile::new(bytes);
+        MIDIFile::new_with_options(file.path(), MIDIReaderMode::Ram, options, None)
+    }
+
+    fn note_track() -> Vec<u8> {
+        TrackBuilder::new()
+            .note_on(0, 0, 60, 100)
+            .note_off(96, 0, 60)
+            .end(0)
+    }
+
+    /// A track chunk declaring `declared` bytes regardless of its data
+    fn track_chunk(declared: u32, data: &[u8]) -> Vec<u8> {
+        let mut bytes = chunk(b"MTrk", data);
+        bytes[4..8].copy_from_slice(&declared.to_be_bytes());
+        bytes
+    }
+
+    fn note_count(midi: &mut MIDIFile) -> u64 {
+        midi.parse_all_tracks(Timeline::Ticks, &NoteFilter::default(), None)
+            .unwrap();
+        midi.stats().as_ref().unwrap().note_count
+    }
+
+    #[test]
+    fn lenient_resync_on_garbage() {
+        // Garbage after a track would be taken as part of it, so it goes
+        // before the first one
+        let mut bytes = header(2, 96);
+        let garbage = bytes.len() as u64;
+        bytes.extend_from_slice(&[0x00, 0xFF, 0x13, 0x37, 0x00, 0x00, 0x00, 0x01, 0xAB]);
+        bytes.extend(chunk(b"MTrk", &note_track()));
+        bytes.extend(chunk(b"MTrk", &note_track()));
+
+        let mut midi = open(&bytes, lenient()).unwrap();
+        assert_eq!(*midi.track_count(), 2);
+        assert_eq!(
+            midi.warnings(),
+            &[LoadWarning::SkippedData {
+                offset: garbage,
+                len: 9
+            }]
+        );
+        assert_eq!(note_count(&mut midi), 2);
+
+        assert!(matches!(
+            open(&bytes, MIDILoadOptions::default()),
+            Err(MIDILoadError::UnexpectedChunk { offset, .. }) if offset == garbage
+        ));
+    }
+
+    #[test]
+    fn chunk_length_mismatch() {
+        // The first track declares two bytes less than it holds
+        let track = note_track();
+        let mut bytes = header(2, 96);
+        bytes.extend(track_chunk(track.len() as u32 - 2, &track));
+        bytes.extend(chunk(b"MTrk", &track));
+
+        let mut midi = open(&bytes, lenient()).unwrap();
+        assert_eq!(*midi.track_count(), 2);
+        assert_eq!(
+            midi.warnings(),
+            &[LoadWarning::ChunkLengthMismatch {
+                track: 0,
+                offset: 14,
+                declared: track.len() as u32 - 2,
+                actual: track.len() as u64,
+            }]
+        );
+        assert_eq!(note_count(&mut midi), 2);
+    }
+
+    #[test]
+    fn tracks_past_the_end() {
+        let track = note_track();
+        let mut bytes = header(1, 96);
+        bytes.extend(track_chunk(track.len() as u32 + 100, &track));
+
+        assert!(matches!(
+            open(&bytes, MIDILoadOptions::default()),
+            Err(MIDILoadError::UnexpectedEnd { offset }) if offset == bytes.len() as u64
+        ));
+
+        let mut midi = open(&bytes, lenient()).unwrap();
+        assert_eq!(
+            midi.warnings(),
+            &[LoadWarning::ChunkLengthMismatch {
+                track: 0,
+                offset: 14,
+                declared: track.len() as u32 + 100,
+                actual: track.len() as u64,
+            }]
+        );
+        assert_eq!(note_count(&mut midi), 1);
+    }
+
+    /// A RIFF chunk with its little-endian length, padded to an even size
+    fn riff_chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut bytes = id[..].to_vec();
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    #[test]
+    fn rmid_files() {
+        let mut form = b"RMID"[..].to_vec();
+        form.extend(riff_chunk(b"INFO", b"odd"));
+        form.extend(riff_chunk(b"data", &smf(96, &[note_track()])));
+        form.extend(riff_chunk(b"DISP", b"not midi"));
+        let bytes = riff_chunk(b"RIFF", &form);
+
+        for &lenient in &[false, true] {
+            let options = MIDILoadOptions {
+                lenient,
+                ..Default::default()
+            };
+            let mut midi = open(&bytes, options).unwrap();
+            assert_eq!(*midi.track_count(), 1);
+            assert!(midi.alien_chunks().is_empty());
+            assert!(midi.warnings().is_empty());
+            assert_eq!(note_count(&mut midi), 1);
+        }
+    }
+
+    #[test]
+    fn alien_chunks_between_tracks() {
+        let mut bytes = header(2, 96);
+        bytes.extend(chunk(b"MTrk", &note_track()));
+        let alien = bytes.len() as u64;
+        bytes.extend(chunk(b"XFIH", b"abc"));
+        bytes.extend(chunk(b"MTrk", &note_track()));
+
+        for &lenient in &[false, true] {
+            let options = MIDILoadOptions {
+                lenient,
+                ..Default::default()
+            };
+            let mut midi = open(&bytes, options).unwrap();
+            assert_eq!(*midi.track_count(), 2);
+            assert_eq!(
+                midi.alien_chunks(),
+                &[AlienChunk {
+                    id: "XFIH".to_string(),
+                    offset: alien,
+                    len: 3
+                }]
+            );
+            assert!(midi.warnings().is_empty());
+            assert_eq!(note_count(&mut midi), 2);
+        }
+    }
+
+    #[test]
+    fn parsed_stats_include_polyphony() {
+        let chord = TrackBuilder::new()
+            .note_on(0, 0, 60, 100)
+            .note_on(0, 0, 64, 100)
+            .note_off(96, 0, 60)
+            .note_off(0, 0, 64)
+            .end(0);
+        let bytes = smf(96, &[chord, note_track()]);
+
+        let mut midi = open(&bytes, MIDILoadOptions::default()).unwrap();
+        assert_eq!(note_count(&mut midi), 3);
+        let peak = midi.stats().as_ref().unwrap().polyphony_peak;
+        assert_eq!(peak, Some(3));
+        assert_eq!(peak, midi.scan_stats().unwrap().polyphony_peak);
+    }
+
+    #[test]
+    fn too_many_segments() {
+        // A single huge delta takes the song past the last segment before any
+        // notes are read
+        let track = TrackBuilder::new()
+            .note_on(0, 0, 60, 100)
+            .note_off(0x0FFF_FFFF, 0, 60)
+            .end(0);
+        let bytes = smf(96, &[track]);
+
+        let mut midi = open(&bytes, MIDILoadOptions::default()).unwrap();
+        let result = midi.parse_all_tracks(Timeline::Ticks, &NoteFilter::default(), None);
+        assert!(matches!(result, Err(MIDILoadError::MIDITooLong)));
+    }
+
+    #[test]
+    fn budgeted_rounds_match_unbounded() {
+        // A note held through the whole song, overlapping notes that end out
+        // of order, and notes left unended until the end of their track, all
+        // sharing keys so each key gets notes from tracks read at different
+        // paces
+        let held = TrackBuilder::new()
+            .note_on(0, 0, 60, 100)
+            .note_off(2000, 0, 60)
+            .end(10);
+        let mut busy = TrackBuilder::new();
+        for i in 0..100 {
+            busy = busy
+                .note_on(0, 1, 58 + i % 5, 100)
+                .note_off(15, 1, 58 + i % 5);
         }
-        output.assert_empty();
+        let stacked = TrackBuilder::new()
+            .note_on(5, 2, 60, 100)
+            .note_on(50, 2, 60, 100)
+            .note_off(300, 2, 60)
+            .note_on(0, 2, 61, 100)
+            .note_off(700, 2, 60)
+            .note_on(0, 2, 62, 100)
+            .end(800);
+        let file = TempFile::new(&smf(96, &[held, busy.end(0), stacked]));
 
-        let trees = trees.into_iter().map(|t| t.complete()).to_vec();
+        let short_segments = |memory_budget| MIDILoadOptions {
+            memory_budget,
+            segment_seconds: 0.1,
+            ..Default::default()
+        };
+        for &timeline in &[Timeline::Ticks, Timeline::Seconds { tps: 1000 }] {
+            let filter = NoteFilter::default();
+            let unbounded = short_segments(usize::MAX);
+            let (midi, trees) =
+                parse(&file, MIDIReaderMode::Ram, unbounded, timeline, &filter).unwrap();
+            let (budgeted, budgeted_trees) = parse(
+                &file,
+                MIDIReaderMode::Ram,
+                short_segments(1),
+                timeline,
+                &filter,
+            )
+            .unwrap();
 
-        // for queue in &mut output.queues {
-        //     let mut tree = TreeSerializer::new(4);
-        //     loop {
-        //         match queue.pop_back() {
-        //             None => break,
-        //             Some(note) => {
-        //                 let note = Rc::try_unwrap(note).expect("Not all notes were ended!");
-        //                 let note = note.into_inner();
-        //                 tree.feed_note(Rc::new(note));
-        //             }
-        //         }
-        //     }
-        //     trees.push(tree.complete());
-        // }
+            assert!(*midi.segment_count() > 10);
+            assert_eq!(midi.segment_count(), budgeted.segment_count());
+            assert_eq!(midi.stats(), budgeted.stats());
+            assert_eq!(midi.stats().as_ref().unwrap().note_count, 105);
+            assert_eq!(tree_values(&trees), tree_values(&budgeted_trees));
+        }
+    }
 
-        let sum: u64 = trees.iter().map(|l| l.count()).sum();
+    #[test]
+    fn sparse_segments() {
+        // A huge delta leaves hundreds of segments with nothing playing
+        let track = TrackBuilder::new()
+            .note_on(0, 0, 60, 100)
+            .note_off(96, 0, 60)
+            .note_on(0x0FFF_FFFF, 0, 61, 100)
+            .note_off(96, 0, 61)
+            .end(0);
+        let file = TempFile::new(&smf(96, &[track]));
+        let options = MIDILoadOptions {
+            segment_seconds: 1400.0,
+            ..Default::default()
+        };
+        let filter = NoteFilter::default();
+        let (midi, trees) = parse(
+            &file,
+            MIDIReaderMode::Ram,
+            options,
+            Timeline::Ticks,
+            &filter,
+        )
+        .unwrap();
 
-        println!("Nodes: {}", sum);
+        let end = 96 + 0x0FFF_FFFF + 96;
+        let segment_count = *midi.segment_count() as usize;
+        assert_eq!(segment_count as i64, end / midi.segment_length() + 1);
+        assert!(segment_count > 500);
+        assert_eq!(trees.len(), segment_count);
+        assert_eq!(midi.stats().as_ref().unwrap().note_count, 2);
 
-        let mut serialized = (0..256)
-            .map(|_| IntVector4::default())
+        // Each key's root points to its own empty leaf
+        let roots = ROOTS_PER_SEGMENT as i32;
+        let empty = (0..roots)
+            .map(|key| [-(roots + key), 0, 0, 0])
+            .chain((0..roots).map(|_| [0, 0, -1, 0]))
             .to_vec();
-        for (i, t) in trees.iter().enumerate() {
-            serialized[i].val1 = t.serialize_to_vec(&mut serialized);
+        let trees = tree_values(&trees);
+        assert_ne!(trees[0], empty);
+        assert_ne!(trees[segment_count - 1], empty);
+        assert!(trees[1..segment_count - 1].iter().all(|b| *b == empty));
+    }
+
+    #[test]
+    fn invalid_segment_seconds() {
+        let bytes = smf(96, &[note_track()]);
+        let with_seconds = |segment_seconds| MIDILoadOptions {
+            segment_seconds,
+            ..Default::default()
+        };
+
+        let subnormal = f64::MIN_POSITIVE / 2.0;
+        for &seconds in &[0.0, -0.0, -1.0, f64::NAN, f64::INFINITY, subnormal] {
+            assert!(matches!(
+                open(&bytes, with_seconds(seconds)),
+                Err(MIDILoadError::InvalidSegmentSeconds { .. })
+            ));
         }
 
-        Ok(serialized)
+        // Segments are at least one unit of the timeline long
+        let mut midi = open(&bytes, with_seconds(1e-300)).unwrap();
+        assert_eq!(note_count(&mut midi), 1);
+        assert_eq!(*midi.segment_length(), 1);
+    }
+
+    #[test]
+    fn notes_span_segments() {
+        let track = TrackBuilder::new()
+            .note_on(10, 0, 60, 100)
+            .note_on(90, 1, 60, 100)
+            .note_off(10, 1, 60)
+            .note_off(90, 0, 60)
+            .end(50);
+        let file = TempFile::new(&smf(96, &[track]));
+        let options = MIDILoadOptions {
+            segment_seconds: 0.1,
+            ..Default::default()
+        };
+        let filter = NoteFilter::default();
+        let (midi, trees) = parse(
+            &file,
+            MIDIReaderMode::Ram,
+            options,
+            Timeline::Ticks,
+            &filter,
+        )
+        .unwrap();
+
+        let length = *midi.segment_length();
+        assert!(length < 50);
+        assert_eq!(trees.len() as i64, 250 / length + 1);
+        for time in 0..250 {
+            let expected = match time {
+                100..=109 => Some((100, 110)),
+                10..=199 => Some((10, 200)),
+                _ => None,
+            };
+            assert_eq!(note_at(&trees, length, 60, time), expected, "at {}", time);
+            assert_eq!(note_at(&trees, length, 61, time), None);
+        }
     }
 }
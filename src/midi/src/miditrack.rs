@@ -1,75 +1,146 @@
 use getset::Getters;
-use std::{ cell::UnsafeCell, collections::VecDeque, rc::Rc};
+use std::{collections::VecDeque, mem};
+
+use crate::{
+    data::Note,
+    errors::MIDILoadError,
+    events::{MidiEvent, TrackEvent},
+    metadata::TrackMetadata,
+    progress::TrackProgress,
+    readers::TrackReader,
+    stats::{TrackStats, TrackStatsCounter},
+};
+
+/// How note-offs are paired with overlapping note-ons of the same key and
+/// channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverlapPolicy {
+    /// A note-off ends the oldest unended note
+    #[default]
+    Fifo,
+    /// A note-off ends the newest unended note
+    Lifo,
+    /// Overlapping note-ons extend the note already playing, which ends once
+    /// every note-on has had its note-off
+    Merge,
+}
 
-use crate::{data::Note, errors::MIDILoadError, readers::TrackReader};
+/// Identifies a note within its key in a `MidiTrackOutput`. Ids count every
+/// note the key has been given, so they stay valid as ended notes are taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NoteId(u64);
 
-#[derive(Getters)]
-pub struct NoteQueues {
-    pub queues: Vec<VecDeque<Rc<UnsafeCell<Note>>>>,
+/// The unended notes of a track, queued by key and channel
+pub(crate) struct NoteQueues {
+    queues: Vec<VecDeque<NoteId>>,
 }
 
-impl NoteQueues {}
+impl NoteQueues {
+    pub fn new() -> Self {
+        NoteQueues {
+            queues: (0..(256 * 16)).map(|_| VecDeque::new()).collect(),
+        }
+    }
+
+    pub fn get_mut(&mut self, key: u8, channel: u8) -> &mut VecDeque<NoteId> {
+        &mut self.queues[key as usize * 16 + channel as usize]
+    }
+
+    /// Ends every queued note at `time`
+    pub fn end_all(self, output: &mut MidiTrackOutput, time: i64) {
+        for (i, queue) in self.queues.into_iter().enumerate() {
+            let key = (i / 16) as u8;
+            for id in queue {
+                output.end_note(key, id, time);
+            }
+        }
+    }
+}
+
+/// A key's notes in the order they started, from the oldest one not yet taken
+struct KeyNotes {
+    notes: VecDeque<Note>,
+    /// Id of the note at the front of `notes`
+    first_id: u64,
+    /// Ends of notes that were taken before they ended
+    late_ends: Vec<(NoteId, i64)>,
+}
 
 #[derive(Getters)]
 pub struct MidiTrackOutput {
-    pub queues: Vec<VecDeque<Rc<UnsafeCell<Note>>>>,
+    keys: Vec<KeyNotes>,
 
     #[getset(get = "pub")]
     note_events_counted: u64,
+}
 
-    #[getset(get = "pub")]
-    last_tempo_time_step: f64,
-
-    ppq: u32,
+impl Default for MidiTrackOutput {
+    fn default() -> Self {
+        MidiTrackOutput::new()
+    }
 }
 
 impl MidiTrackOutput {
-    pub fn new(ppq: u32) -> Self {
-        let mut queues = Vec::<VecDeque<Rc<UnsafeCell<Note>>>>::new();
-
-        for _ in 0..(256) {
-            queues.push(VecDeque::new());
+    pub fn new() -> Self {
+        let keys = (0..256)
+            .map(|_| KeyNotes {
+                notes: VecDeque::new(),
+                first_id: 0,
+                late_ends: Vec::new(),
+            })
+            .collect();
+
+        MidiTrackOutput {
+            keys,
+            note_events_counted: 0,
         }
+    }
 
-        let mut output = MidiTrackOutput {
-            queues,
-            last_tempo_time_step: 0 as f64,
-            note_events_counted: 0,
-            ppq,
-        };
-        output.update_tempo(500000);
-        output
+    pub fn add_note(&mut self, key: u8, note: Note) -> NoteId {
+        let key = &mut self.keys[key as usize];
+        let id = NoteId(key.first_id + key.notes.len() as u64);
+        key.notes.push_back(note);
+        id
     }
 
-    pub fn add_note(&mut self, key: u8, note: Rc<UnsafeCell<Note>>) {
-        self.queues[key as usize].push_front(note);
+    /// Sets the end of a note, or keeps it for `take_late_ends` if the note
+    /// was already taken
+    pub fn end_note(&mut self, key: u8, id: NoteId, time: i64) {
+        let key = &mut self.keys[key as usize];
+        match id.0.checked_sub(key.first_id) {
+            Some(index) => key.notes[index as usize].end = time,
+            None => key.late_ends.push((id, time)),
+        }
     }
 
     pub fn note_count(&self) -> u64 {
-        self.queues.iter().map(|q| q.len() as u64).sum()
+        self.keys.iter().map(|k| k.notes.len() as u64).sum()
     }
 
-    pub fn flush_notes(&mut self, key: i32, queue: &mut VecDeque<Note>) {
-        let source = &mut self.queues[key as usize];
+    /// Moves the key's notes that start before `time` to `queue` along with
+    /// their ids, ordered by start time. Notes that are still playing are
+    /// moved too, and their ends are kept for `take_late_ends`.
+    pub fn flush_notes(&mut self, key: i32, time: i64, queue: &mut Vec<(NoteId, Note)>) {
+        let source = &mut self.keys[key as usize];
 
-        loop {
-            match source.back() {
-                None => break,
-                Some(note) => unsafe {
-                    if (*note.get()).end == -1 {
-                        break;
-                    }
-                    let note = source.pop_back().unwrap();
-                    let note = Rc::try_unwrap(note).expect("Not all notes were ended!");
-                    let note = note.into_inner();
-                    queue.push_front(note);
-                },
+        while let Some(note) = source.notes.front() {
+            if note.start >= time {
+                break;
             }
+            let id = NoteId(source.first_id);
+            queue.push((id, source.notes.pop_front().unwrap()));
+            source.first_id += 1;
         }
     }
 
+    /// Takes the ends of the key's notes that ended after they were flushed
+    pub fn take_late_ends(&mut self, key: i32) -> Vec<(NoteId, i64)> {
+        mem::take(&mut self.keys[key as usize].late_ends)
+    }
+
     pub fn assert_empty(&self) {
-        debug_assert!(self.queues.iter().map(|q| q.len()).sum::<usize>() == 0);
+        debug_assert!(self.note_count() == 0);
+        debug_assert!(self.keys.iter().all(|k| k.late_ends.is_empty()));
     }
 
     pub fn count_note_event(&mut self) {
@@ -79,24 +150,27 @@ impl MidiTrackOutput {
     pub fn reset_note_event_counted(&mut self) {
         self.note_events_counted = 0;
     }
-
-    pub fn update_tempo(&mut self, tempo: u32) {
-        self.last_tempo_time_step = (tempo as f64 / self.ppq as f64) / 1000000.0;
-    }
 }
 
+/// A track's `(tick, tempo)` changes, metadata and statistics, as found by
+/// `MIDITrack::read_global_events`
+pub(crate) type GlobalEvents = (Vec<(u64, u32)>, TrackMetadata, TrackStats);
+
 pub struct MIDITrack {
     track_id: u32,
 
     ended: bool,
+    has_end_event: bool,
     reader: Box<dyn TrackReader>,
     has_read_delta: bool,
     next_event_pos: u64,
-    pos: u64,
+    last_delta: u32,
+    last_time_int: i64,
     pushback: i32,
     prev_command: u8,
+    overlap_policy: OverlapPolicy,
 
-    unended_notes: Option<Vec<VecDeque<Rc<UnsafeCell<Note>>>>>,
+    unended_notes: Option<NoteQueues>,
 }
 
 impl MIDITrack {
@@ -106,32 +180,26 @@ impl MIDITrack {
 
             reader,
             ended: false,
+            has_end_event: false,
             has_read_delta: false,
             next_event_pos: 0,
-            pos: 0,
+            last_delta: 0,
+            last_time_int: 0,
             pushback: -1,
             prev_command: 0,
+            overlap_policy: OverlapPolicy::default(),
             unended_notes: None,
         }
     }
 
-    fn init_unended_queues() -> Vec<VecDeque<Rc<UnsafeCell<Note>>>> {
-        let mut unended_notes = Vec::new();
-
-        for _ in 0..(256 * 16) {
-            unended_notes.push(VecDeque::new());
-        }
-
-        unended_notes
+    pub fn set_overlap_policy(&mut self, policy: OverlapPolicy) {
+        self.overlap_policy = policy;
     }
 
-    fn get_unended_queue_mut(&mut self, key: u8, chan: u8) -> &mut VecDeque<Rc<UnsafeCell<Note>>> {
-        if self.unended_notes.is_none() {
-            self.unended_notes.replace(MIDITrack::init_unended_queues());
-        }
-
-        let unended_notes = self.unended_notes.as_mut().unwrap();
-        &mut unended_notes[(key as u32 * 16 + chan as u32) as usize]
+    fn get_unended_queue_mut(&mut self, key: u8, chan: u8) -> &mut VecDeque<NoteId> {
+        self.unended_notes
+            .get_or_insert_with(NoteQueues::new)
+            .get_mut(key, chan)
     }
 
     #[inline]
@@ -166,53 +234,83 @@ impl MIDITrack {
         return Ok(val);
     }
 
-    fn end_note(note: Rc<UnsafeCell<Note>>, time: i32) {
-        unsafe {
-            let note = note.as_ref();
-            (*note.get()).end = time;
+    /// Ends the track, along with any of its notes that are still playing
+    fn end_track(&mut self, output: &mut MidiTrackOutput, time_int: i64) {
+        self.ended = true;
+        if let Some(unended_notes) = self.unended_notes.take() {
+            unended_notes.end_all(output, time_int);
         }
     }
 
-    fn end_track(&mut self, time_int: i32) {
+    /// Ends a track that was read without producing notes
+    fn end_events(&mut self) {
+        debug_assert!(self.unended_notes.is_none());
         self.ended = true;
-        match &mut self.unended_notes {
-            Some(unended_notes) => {
-                for k in unended_notes {
-                    loop {
-                        let n = k.pop_back();
-                        match n {
-                            Some(n) => MIDITrack::end_note(n, time_int),
-                            None => break,
-                        }
-                    }
-                }
-            }
-            None => {}
-        }
-        self.unended_notes.take();
     }
 
     pub fn ended(&self) -> bool {
         self.ended
     }
 
+    /// Whether the track was ended by an end-of-track event, rather than by
+    /// running out of data
+    pub fn has_end_event(&self) -> bool {
+        self.has_end_event
+    }
+
+    /// File offset the track's reader has reached
+    pub fn position(&self) -> u64 {
+        self.reader.position()
+    }
+
+    /// Tick of the next unread event, or `None` once the track has ended. Running
+    /// out of data here ends the track at the time of the last read tick.
+    pub fn next_event_tick(
+        &mut self,
+        output: &mut MidiTrackOutput,
+    ) -> Result<Option<u64>, MIDILoadError> {
+        if self.ended {
+            return Ok(None);
+        }
+
+        if !self.has_read_delta {
+            match self.read_delta() {
+                Err(MIDILoadError::OutOfBoundsError) => {
+                    self.end_track(output, self.last_time_int);
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+                Ok(_) => {}
+            }
+        }
+
+        Ok(Some(self.next_event_pos))
+    }
+
+    /// Reads every event on the tick returned by `next_event_tick`
     pub fn read_tick(
         &mut self,
         output: &mut MidiTrackOutput,
-        time_int: i32,
+        time_int: i64,
     ) -> Result<(), MIDILoadError> {
-        debug_assert!(self.ended == false);
+        debug_assert!(!self.ended);
+        debug_assert!(self.has_read_delta);
 
-        let mut read = || -> Result<(), MIDILoadError> {
-            if !self.has_read_delta {
-                self.read_delta()?;
-            }
+        self.last_time_int = time_int;
+        let tick = self.next_event_pos;
 
-            while self.next_event_pos < self.pos {
+        let mut read = || -> Result<(), MIDILoadError> {
+            loop {
                 self.read_event(output, time_int)?;
+                if self.ended {
+                    break;
+                }
+
                 self.read_delta()?;
+                if self.next_event_pos != tick {
+                    break;
+                }
             }
-            self.pos += 1;
 
             Ok(())
         };
@@ -220,7 +318,7 @@ impl MIDITrack {
         match read() {
             Err(e) => match e {
                 MIDILoadError::OutOfBoundsError => {
-                    self.end_track(time_int);
+                    self.end_track(output, time_int);
                     Ok(())
                 }
                 e => Err(e),
@@ -230,20 +328,49 @@ impl MIDITrack {
     }
 
     fn read_delta(&mut self) -> Result<(), MIDILoadError> {
-        debug_assert!(self.has_read_delta == false);
+        debug_assert!(!self.has_read_delta);
 
-        self.next_event_pos += self.read_variable_len()? as u64;
+        self.last_delta = self.read_variable_len()?;
+        self.next_event_pos += self.last_delta as u64;
         self.has_read_delta = true;
 
         Ok(())
     }
 
-    fn read_event(
-        &mut self,
-        output: &mut MidiTrackOutput,
-        time_int: i32,
-    ) -> Result<(), MIDILoadError> {
-        debug_assert!(self.has_read_delta == true);
+    fn read_data(&mut self, len: u32) -> Result<Vec<u8>, MIDILoadError> {
+        // The length comes from the file, so a corrupt one mustn't reserve more
+        // than the track could still hold
+        let capacity = (len as u64).min(self.reader.remaining());
+        let mut data = Vec::with_capacity(capacity as usize);
+        for _ in 0..len {
+            data.push(self.read_fast()?);
+        }
+        Ok(data)
+    }
+
+    fn skip_data(&mut self, len: u32) -> Result<(), MIDILoadError> {
+        for _ in 0..len {
+            self.read_fast()?;
+        }
+        Ok(())
+    }
+
+    /// Reads the data of a sysex, escape or meta event, or skips it and
+    /// returns an empty `Vec` if `keep_data` is false
+    fn event_data(&mut self, len: u32, keep_data: bool) -> Result<Vec<u8>, MIDILoadError> {
+        if keep_data {
+            self.read_data(len)
+        } else {
+            self.skip_data(len)?;
+            Ok(Vec::new())
+        }
+    }
+
+    /// Decodes the event following an already-read delta, consuming all of its
+    /// bytes. Without `keep_data`, sysex, escape and meta events other than
+    /// tempo come back with empty data, so skipping them doesn't allocate.
+    fn read_track_event(&mut self, keep_data: bool) -> Result<MidiEvent, MIDILoadError> {
+        debug_assert!(self.has_read_delta);
         self.has_read_delta = false;
 
         let mut command = self.read()?;
@@ -254,90 +381,393 @@ impl MIDITrack {
         self.prev_command = command;
 
         let comm = command & 0xF0;
+        let channel = command & 0x0F;
 
-        match comm {
-            0x90 | 0x80 => {
-                let channel = command & 0x0F;
-                let key = self.read()?;
-                let vel = self.read_fast()?;
-
-                output.count_note_event();
-
-                if comm == 0x80 || vel == 0 {
-                    let l = self.get_unended_queue_mut(key, channel);
-                    let note = l.pop_back();
-                    match note {
-                        None => {}
-                        Some(note) => MIDITrack::end_note(note, time_int),
-                    }
-                } else {
-                    let n = Note::new_unended(time_int, self.track_id, channel);
-                    let n = Rc::new(UnsafeCell::new(n));
-                    output.add_note(key, n.clone());
-                    let queue = self.get_unended_queue_mut(key, channel);
-                    queue.push_front(n);
-                }
-            }
-
-            0xA0 => {
-                self.read()?;
-                self.read_fast()?;
-            }
-            0xB0 => {
-                self.read()?;
-                self.read_fast()?;
-            }
-            0xC0 => {
-                self.read()?;
-            }
-            0xD0 => {
-                self.read()?;
-            }
+        let event = match comm {
+            0x80 => MidiEvent::NoteOff {
+                channel,
+                key: self.read()?,
+                velocity: self.read_fast()?,
+            },
+            0x90 => MidiEvent::NoteOn {
+                channel,
+                key: self.read()?,
+                velocity: self.read_fast()?,
+            },
+            0xA0 => MidiEvent::PolyphonicAftertouch {
+                channel,
+                key: self.read()?,
+                pressure: self.read_fast()?,
+            },
+            0xB0 => MidiEvent::ControlChange {
+                channel,
+                controller: self.read()?,
+                value: self.read_fast()?,
+            },
+            0xC0 => MidiEvent::ProgramChange {
+                channel,
+                program: self.read()?,
+            },
+            0xD0 => MidiEvent::ChannelAftertouch {
+                channel,
+                pressure: self.read()?,
+            },
             0xE0 => {
-                self.read()?;
-                self.read_fast()?;
+                let lsb = self.read()? as u16;
+                let msb = self.read_fast()? as u16;
+                MidiEvent::PitchBend {
+                    channel,
+                    value: (msb << 7) | lsb,
+                }
             }
             _ => match command {
-                0xF0 => while self.read()? != 0b11110111 {},
-                0b11110010 => {
-                    self.read()?;
-                    self.read_fast()?;
+                0xF0 => {
+                    let len = self.read_variable_len()?;
+                    MidiEvent::SystemExclusive(self.event_data(len, keep_data)?)
                 }
-                0b11110011 => {
-                    self.read()?;
+                0xF7 => {
+                    let len = self.read_variable_len()?;
+                    MidiEvent::Escape(self.event_data(len, keep_data)?)
                 }
+                0b11110010 => {
+                    let lsb = self.read()? as u16;
+                    let msb = self.read_fast()? as u16;
+                    MidiEvent::SongPosition((msb << 7) | lsb)
+                }
+                0b11110011 => MidiEvent::SongSelect(self.read()?),
                 0xFF => {
-                    let command = self.read()?;
+                    let kind = self.read()?;
                     let size = self.read_variable_len()?;
-                    match command {
+                    match kind {
                         0x2F => {
-                            self.end_track(time_int);
+                            self.skip_data(size)?;
+                            MidiEvent::EndOfTrack
                         }
-                        0x51 => {
-                            if size != 3 {
-                                self.end_track(time_int);
-                            }
-
+                        0x51 if size == 3 => {
                             let mut btempo = 0 as u32;
                             for _ in 0..3 {
                                 btempo = (btempo << 8) | self.read_fast()? as u32;
                             }
 
-                            output.update_tempo(btempo);
-                        }
-                        _ => {
-                            for _ in 0..size {
-                                self.read_fast()?;
-                            }
+                            MidiEvent::Tempo(btempo)
                         }
+                        _ => MidiEvent::from_meta(kind, self.event_data(size, keep_data)?),
                     }
                 }
-                _ => {
-                    // undefined event
-                }
+                command => MidiEvent::Undefined(command),
             },
+        };
+
+        Ok(event)
+    }
+
+    fn read_event(
+        &mut self,
+        output: &mut MidiTrackOutput,
+        time_int: i64,
+    ) -> Result<(), MIDILoadError> {
+        match self.read_track_event(false)? {
+            MidiEvent::NoteOn {
+                channel,
+                key,
+                velocity,
+            } if velocity > 0 => {
+                output.count_note_event();
+
+                let policy = self.overlap_policy;
+                let track_id = self.track_id;
+                let queue = self.get_unended_queue_mut(key, channel);
+
+                // When merging, the playing note is queued again so that it
+                // takes one more note-off to end
+                if policy == OverlapPolicy::Merge && !queue.is_empty() {
+                    let playing = *queue.front().unwrap();
+                    queue.push_front(playing);
+                    return Ok(());
+                }
+
+                let n = Note::new_unended(time_int, track_id, channel, velocity);
+                queue.push_front(output.add_note(key, n));
+            }
+            MidiEvent::NoteOn { channel, key, .. } | MidiEvent::NoteOff { channel, key, .. } => {
+                output.count_note_event();
+
+                let policy = self.overlap_policy;
+                let l = self.get_unended_queue_mut(key, channel);
+                let note = match policy {
+                    OverlapPolicy::Fifo | OverlapPolicy::Merge => l.pop_back(),
+                    OverlapPolicy::Lifo => l.pop_front(),
+                };
+                match note {
+                    None => {}
+                    // A merged note only ends on its last note-off
+                    Some(_) if policy == OverlapPolicy::Merge && !l.is_empty() => {}
+                    Some(note) => output.end_note(key, note, time_int),
+                }
+            }
+            MidiEvent::EndOfTrack => {
+                self.has_end_event = true;
+                self.end_track(output, time_int);
+            }
+            // Tempo is applied by the caller through the file's tempo map
+            _ => {}
         }
 
         Ok(())
     }
+
+    /// Reads the next event, or `None` once the track has ended. Running out
+    /// of data ends the track without an error.
+    pub fn read_next_event(&mut self) -> Result<Option<TrackEvent>, MIDILoadError> {
+        if self.ended {
+            return Ok(None);
+        }
+
+        let mut read = || -> Result<TrackEvent, MIDILoadError> {
+            if !self.has_read_delta {
+                self.read_delta()?;
+            }
+            let delta = self.last_delta;
+            let tick = self.next_event_pos;
+            let event = self.read_track_event(true)?;
+            Ok(TrackEvent { delta, tick, event })
+        };
+
+        match read() {
+            Ok(event) => {
+                if event.event == MidiEvent::EndOfTrack {
+                    self.has_end_event = true;
+                    self.end_events();
+                }
+                Ok(Some(event))
+            }
+            Err(MIDILoadError::OutOfBoundsError) => {
+                self.end_events();
+                Ok(None)
+            }
+            Err(e) => {
+                self.end_events();
+                Err(e.in_track(self.track_id, self.position()))
+            }
+        }
+    }
+
+    /// Iterates over every remaining event of the track
+    pub fn into_events(self) -> TrackEvents {
+        TrackEvents { track: self }
+    }
+
+    /// Scans the whole track for events that apply to the whole file, returning
+    /// its `(tick, tempo)` changes in the order they appear along with its
+    /// metadata and statistics. Consumes the track's reader.
+    pub(crate) fn read_global_events(
+        mut self,
+        mut progress: TrackProgress,
+    ) -> Result<GlobalEvents, MIDILoadError> {
+        let mut changes = Vec::new();
+        let mut metadata = TrackMetadata::new(self.track_id);
+        let mut stats = TrackStatsCounter::new(self.overlap_policy);
+
+        while let Some(event) = self.read_next_event()? {
+            progress.update(self.position())?;
+            stats.record(&event);
+            if let MidiEvent::Tempo(tempo) = event.event {
+                changes.push((event.tick, tempo));
+            } else {
+                metadata.record(event);
+            }
+        }
+
+        progress.finish();
+        stats.finish();
+
+        Ok((changes, metadata, stats.into_stats()))
+    }
+
+    /// Reads the track's changes in sounding notes tick by tick, without
+    /// holding on to them
+    pub(crate) fn into_polyphony_changes(self) -> PolyphonyChanges {
+        PolyphonyChanges {
+            counter: Some(TrackStatsCounter::new(self.overlap_policy)),
+            track: self,
+        }
+    }
+}
+
+/// A track's `(tick, change)` pairs in sounding notes, in tick order
+pub(crate) struct PolyphonyChanges {
+    track: MIDITrack,
+    /// `None` once the track has ended
+    counter: Option<TrackStatsCounter>,
+}
+
+impl Iterator for PolyphonyChanges {
+    type Item = Result<(u64, i32), MIDILoadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let counter = self.counter.as_mut()?;
+        loop {
+            match self.track.read_next_event() {
+                Ok(Some(event)) => {
+                    if let Some(change) = counter.record(&event) {
+                        return Some(Ok(change));
+                    }
+                }
+                Ok(None) => {
+                    let change = counter.finish();
+                    self.counter = None;
+                    return change.map(Ok);
+                }
+                Err(e) => {
+                    self.counter = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+pub struct TrackEvents {
+    track: MIDITrack,
+}
+
+impl Iterator for TrackEvents {
+    type Item = Result<TrackEvent, MIDILoadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.track.read_next_event().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::readers::FullRamTrackReader;
+
+    fn open_track(bytes: &[u8], policy: OverlapPolicy) -> MIDITrack {
+        let mut track = MIDITrack::new(Box::new(FullRamTrackReader::new(bytes.to_vec())), 0);
+        track.set_overlap_policy(policy);
+        track
+    }
+
+    /// Reads the whole track with ticks as times
+    fn read_all(track: &mut MIDITrack, output: &mut MidiTrackOutput) {
+        while let Some(tick) = track.next_event_tick(output).unwrap() {
+            track.read_tick(output, tick as i64).unwrap();
+        }
+    }
+
+    /// Takes every note of the output as `(key, start, end)`
+    fn take_notes(output: &mut MidiTrackOutput) -> Vec<(u8, i64, i64)> {
+        let mut queue = Vec::new();
+        let mut notes = Vec::new();
+        for key in 0..256 {
+            output.flush_notes(key, i64::MAX, &mut queue);
+            for (_, note) in queue.drain(..) {
+                notes.push((key as u8, note.start, note.end));
+            }
+        }
+        output.assert_empty();
+        notes
+    }
+
+    fn notes(bytes: &[u8], policy: OverlapPolicy) -> Vec<(u8, i64, i64)> {
+        let mut output = MidiTrackOutput::new();
+        read_all(&mut open_track(bytes, policy), &mut output);
+        take_notes(&mut output)
+    }
+
+    /// Two note-ons of key 60 at 0 and 10, with note-offs at 20 and 30
+    const STACKED: &[u8] = &[
+        0x00, 0x90, 60, 64, //
+        0x0A, 0x90, 60, 64, //
+        0x0A, 0x80, 60, 0, //
+        0x0A, 0x80, 60, 0, //
+        0x00, 0xFF, 0x2F, 0x00,
+    ];
+
+    #[test]
+    fn stacked_notes() {
+        assert_eq!(
+            notes(STACKED, OverlapPolicy::Fifo),
+            [(60, 0, 20), (60, 10, 30)]
+        );
+        assert_eq!(
+            notes(STACKED, OverlapPolicy::Lifo),
+            [(60, 0, 30), (60, 10, 20)]
+        );
+        assert_eq!(notes(STACKED, OverlapPolicy::Merge), [(60, 0, 30)]);
+    }
+
+    #[test]
+    fn zero_velocity_note_on_ends_note() {
+        // The second note-on uses running status
+        let bytes = [0x00, 0x90, 60, 64, 0x0A, 60, 0, 0x00, 0xFF, 0x2F, 0x00];
+        assert_eq!(notes(&bytes, OverlapPolicy::Fifo), [(60, 0, 10)]);
+    }
+
+    #[test]
+    fn unmatched_note_offs() {
+        let bytes = [
+            0x00, 0x80, 60, 0, // before any note
+            0x00, 0x90, 60, 64, //
+            0x05, 0x81, 60, 0, // on another channel
+            0x05, 0x80, 60, 0, //
+            0x05, 0x80, 60, 0, // after the note ended
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        for &policy in &[
+            OverlapPolicy::Fifo,
+            OverlapPolicy::Lifo,
+            OverlapPolicy::Merge,
+        ] {
+            assert_eq!(notes(&bytes, policy), [(60, 0, 10)]);
+        }
+    }
+
+    #[test]
+    fn end_of_track_ends_notes() {
+        let bytes = [
+            0x00, 0x90, 60, 64, //
+            0x00, 0x90, 62, 64, //
+            0x0A, 0x80, 60, 0, //
+            0x0A, 0xFF, 0x2F, 0x00,
+        ];
+        let mut output = MidiTrackOutput::new();
+        let mut track = open_track(&bytes, OverlapPolicy::Fifo);
+        read_all(&mut track, &mut output);
+        assert!(track.has_end_event());
+        assert_eq!(take_notes(&mut output), [(60, 0, 10), (62, 0, 20)]);
+
+        // Running out of data ends notes at the last tick read
+        let bytes = [0x00, 0x90, 60, 64, 0x0A, 0x80, 62, 0];
+        let mut output = MidiTrackOutput::new();
+        let mut track = open_track(&bytes, OverlapPolicy::Fifo);
+        read_all(&mut track, &mut output);
+        assert!(!track.has_end_event());
+        assert_eq!(take_notes(&mut output), [(60, 0, 10)]);
+    }
+
+    #[test]
+    fn late_ends() {
+        let mut output = MidiTrackOutput::new();
+        let mut track = open_track(STACKED, OverlapPolicy::Fifo);
+
+        // Flush both notes once they've started, before either has ended
+        for _ in 0..2 {
+            let tick = track.next_event_tick(&mut output).unwrap().unwrap();
+            track.read_tick(&mut output, tick as i64).unwrap();
+        }
+        let mut queue = Vec::new();
+        output.flush_notes(60, 11, &mut queue);
+        assert_eq!(queue.len(), 2);
+        assert!(queue.iter().all(|(_, n)| n.unended()));
+        assert!(output.take_late_ends(60).is_empty());
+
+        read_all(&mut track, &mut output);
+        let ids: Vec<_> = queue.iter().map(|&(id, _)| id).collect();
+        assert_eq!(output.take_late_ends(60), [(ids[0], 20), (ids[1], 30)]);
+        assert_eq!(output.note_count(), 0);
+        output.assert_empty();
+    }
 }
@@ -0,0 +1,156 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::errors::MIDILoadError;
+
+/// How many bytes a track reads between progress reports and cancellation
+/// checks
+const TRACK_REPORT_INTERVAL: u64 = 1 << 16;
+
+/// The stages of loading a file, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadPhase {
+    /// Walking the chunk headers, counted in bytes of the file
+    DiscoveringChunks,
+    /// Reading tempo, metadata and statistics, counted in bytes of track data
+    TempoPass,
+    /// Reading notes, counted in bytes of track data
+    Parsing,
+    /// Building the per-key trees, counted in keys
+    BuildingTrees,
+    /// Sending the segments around the start of the view to the GPU, counted
+    /// in bytes. Later segments are uploaded as the view reaches them.
+    Uploading,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadProgress {
+    pub phase: LoadPhase,
+    pub processed: u64,
+    pub total: u64,
+}
+
+/// Receives progress while a file loads. Reports can come from several
+/// threads at once while tracks are read in parallel.
+pub trait ProgressSink: Sync {
+    fn report(&self, progress: LoadProgress);
+}
+
+impl<F: Fn(LoadProgress) + Sync> ProgressSink for F {
+    fn report(&self, progress: LoadProgress) {
+        self(progress)
+    }
+}
+
+/// A flag that aborts a load with `MIDILoadError::Cancelled`. Clones share
+/// the same flag, so one can be kept to cancel a load running elsewhere.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn check(&self) -> Result<(), MIDILoadError> {
+        match self.is_cancelled() {
+            true => Err(MIDILoadError::Cancelled),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Progress through one phase, shared between the threads working on it
+pub(crate) struct PhaseProgress<'a> {
+    sink: Option<&'a dyn ProgressSink>,
+    phase: LoadPhase,
+    processed: AtomicU64,
+    total: u64,
+}
+
+impl<'a> PhaseProgress<'a> {
+    pub fn new(sink: Option<&'a dyn ProgressSink>, phase: LoadPhase, total: u64) -> Self {
+        let progress = PhaseProgress {
+            sink,
+            phase,
+            processed: AtomicU64::new(0),
+            total,
+        };
+        progress.send(0);
+        progress
+    }
+
+    fn send(&self, processed: u64) {
+        if let Some(sink) = self.sink {
+            sink.report(LoadProgress {
+                phase: self.phase,
+                processed,
+                total: self.total,
+            });
+        }
+    }
+
+    pub fn add(&self, amount: u64) {
+        let processed = self.processed.fetch_add(amount, Ordering::Relaxed) + amount;
+        self.send(processed);
+    }
+
+    pub fn set(&self, processed: u64) {
+        self.processed.store(processed, Ordering::Relaxed);
+        self.send(processed);
+    }
+}
+
+/// Reports a single track's progress through a phase as its reader moves,
+/// checking for cancellation at the same time
+pub(crate) struct TrackProgress<'a, 'b> {
+    phase: &'a PhaseProgress<'b>,
+    cancel: &'a CancelToken,
+    reported: u64,
+    end: u64,
+}
+
+impl<'a, 'b> TrackProgress<'a, 'b> {
+    pub fn new(
+        phase: &'a PhaseProgress<'b>,
+        cancel: &'a CancelToken,
+        start: u64,
+        len: u64,
+    ) -> Self {
+        TrackProgress {
+            phase,
+            cancel,
+            reported: start,
+            end: start + len,
+        }
+    }
+
+    #[inline]
+    pub fn update(&mut self, position: u64) -> Result<(), MIDILoadError> {
+        if position >= self.reported + TRACK_REPORT_INTERVAL {
+            self.phase.add(position - self.reported);
+            self.reported = position;
+            self.cancel.check()?;
+        }
+        Ok(())
+    }
+
+    /// Reports the rest of the track, however much of it was actually read
+    pub fn finish(self) {
+        if self.end > self.reported {
+            self.phase.add(self.end - self.reported);
+        }
+    }
+}
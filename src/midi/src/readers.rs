@@ -1,14 +1,23 @@
 use std::{
     fs::File,
     io::{self, Read, Seek, SeekFrom},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
+use memmap2::Mmap;
+
 use crate::errors::MIDILoadError;
 
+/// Size of the per-track read buffer used when streaming tracks from disk
+const DISK_TRACK_BUFFER_SIZE: u64 = 1 << 15;
+
 pub struct DiskReader {
     reader: File,
     length: u64,
+
+    /// Separate handle shared by all track readers, so their seeks don't
+    /// disturb the header reader's position
+    track_file: Arc<Mutex<File>>,
 }
 
 pub struct RAMReader {
@@ -16,71 +25,123 @@ pub struct RAMReader {
     pos: usize,
 }
 
-fn e<T>(val: Result<T, io::Error>) -> Result<T, MIDILoadError> {
-    match val {
-        Ok(e) => Ok(e),
-        Err(_) => Err(MIDILoadError::UnknownFilesystemError),
-    }
+/// Maps the whole file into memory and lets the OS page it in on demand
+pub struct MmapReader {
+    mmap: Arc<Mmap>,
+    pos: usize,
+}
+
+fn e<T>(val: Result<T, io::Error>, offset: u64) -> Result<T, MIDILoadError> {
+    val.map_err(|err| MIDILoadError::from_io(err, offset))
+}
+
+fn open_file(filename: &str) -> Result<File, MIDILoadError> {
+    File::open(filename).map_err(MIDILoadError::NotFound)
 }
 
 fn get_reader_len(reader: &mut File) -> Result<u64, MIDILoadError> {
-    let mut get = || {
-        let pos = reader.seek(SeekFrom::End(0))?;
-        reader.seek(SeekFrom::Start(0))?;
-        return Ok(pos);
-    };
+    let pos = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(pos)
+}
 
-    e(get())
+fn check_header(expected: &str, found: &[u8], offset: u64) -> Result<(), MIDILoadError> {
+    if expected.as_bytes() == found {
+        Ok(())
+    } else {
+        Err(MIDILoadError::UnexpectedChunk {
+            expected: expected.to_string(),
+            found: String::from_utf8_lossy(found).into_owned(),
+            offset,
+        })
+    }
+}
+
+fn check_chunk_bounds(start: u64, len: u64, file_len: u64) -> Result<(), MIDILoadError> {
+    if start + len > file_len {
+        Err(MIDILoadError::ChunkOutOfBounds {
+            offset: start,
+            len,
+            file_len,
+        })
+    } else {
+        Ok(())
+    }
 }
 
 impl DiskReader {
     pub fn new(filename: &str) -> Result<DiskReader, MIDILoadError> {
-        let reader_maybe = File::open(filename);
-        if reader_maybe.is_err() {
-            return Err(MIDILoadError::NotFound);
-        }
-
-        let mut reader = reader_maybe.unwrap();
+        let mut reader = open_file(filename)?;
+        let track_file = Arc::new(Mutex::new(open_file(filename)?));
 
-        let len = get_reader_len(&mut reader);
+        let length = get_reader_len(&mut reader)?;
 
-        match len {
-            Err(_) => Err(MIDILoadError::UnknownFilesystemError),
-            Ok(length) => Ok(DiskReader { reader, length }),
-        }
+        Ok(DiskReader {
+            reader,
+            length,
+            track_file,
+        })
     }
 }
 
 impl RAMReader {
     pub fn new(filename: &str) -> Result<RAMReader, MIDILoadError> {
-        let reader_maybe = File::open(filename);
-        if reader_maybe.is_err() {
-            return Err(MIDILoadError::NotFound);
-        }
+        let mut reader = open_file(filename)?;
 
-        let mut reader = reader_maybe.unwrap();
+        let length = get_reader_len(&mut reader)?;
 
-        let len = get_reader_len(&mut reader);
+        let mut bytes = vec![0; length as usize];
+        e(reader.read_exact(&mut bytes), 0)?;
+        Ok(RAMReader {
+            bytes: Arc::new(bytes),
+            pos: 0,
+        })
+    }
 
-        match len {
-            Err(_) => Err(MIDILoadError::UnknownFilesystemError),
-            Ok(length) => {
-                let mut bytes = vec![0; length as usize];
-                e(reader.read(&mut bytes))?;
-                Ok(RAMReader {
-                    bytes: Arc::new(bytes),
-                    pos: 0,
-                })
+    pub fn read_byte(&mut self) -> Result<u8, MIDILoadError> {
+        let b = self.bytes.get(self.pos);
+        match b {
+            Some(v) => {
+                self.pos += 1;
+                Ok(*v)
             }
+            None => Err(MIDILoadError::UnexpectedEnd {
+                offset: self.pos as u64,
+            }),
         }
     }
+}
+
+impl MmapReader {
+    pub fn new(filename: &str) -> Result<MmapReader, MIDILoadError> {
+        let reader = open_file(filename)?;
+
+        // Empty files can't be mapped, and hold no header for the other
+        // readers either
+        if reader.metadata()?.len() == 0 {
+            return Err(MIDILoadError::UnexpectedEnd { offset: 0 });
+        }
+
+        // Safety: the mapping is only ever read, and the file is expected to
+        // stay unmodified for as long as it is being parsed
+        let mmap = unsafe { Mmap::map(&reader) }?;
+
+        Ok(MmapReader {
+            mmap: Arc::new(mmap),
+            pos: 0,
+        })
+    }
 
     pub fn read_byte(&mut self) -> Result<u8, MIDILoadError> {
-        let b = self.bytes.get(self.pos);
-        self.pos += 1;
+        let b = self.mmap.get(self.pos);
         match b {
-            Some(v) => Ok(*v),
-            None => Err(MIDILoadError::CorruptChunks),
+            Some(v) => {
+                self.pos += 1;
+                Ok(*v)
+            }
+            None => Err(MIDILoadError::UnexpectedEnd {
+                offset: self.pos as u64,
+            }),
         }
     }
 }
@@ -89,36 +150,36 @@ pub trait MIDIReader {
     fn assert_header(&mut self, text: &str) -> Result<(), MIDILoadError>;
     fn read_value(&mut self, bytes: i32) -> Result<u32, MIDILoadError>;
     fn get_position(&mut self) -> Result<u64, MIDILoadError>;
-    fn is_end(&mut self) -> Result<bool, MIDILoadError>;
-    fn skip(&mut self, bytes: u64) -> Result<u64, MIDILoadError>;
-
-    fn open_reader(&self, start: u64, len: u64, ram_cache: bool) -> Box<dyn TrackReader>;
+    fn seek(&mut self, pos: u64) -> Result<(), MIDILoadError>;
+    fn length(&self) -> u64;
+
+    /// Finds the first occurrence of `pattern` at or after `from`. The reader's
+    /// position is unspecified afterwards.
+    fn find(&mut self, pattern: &[u8], from: u64) -> Result<Option<u64>, MIDILoadError>;
+
+    fn open_reader(
+        &self,
+        start: u64,
+        len: u64,
+        ram_cache: bool,
+    ) -> Result<Box<dyn TrackReader>, MIDILoadError>;
 }
 
 impl MIDIReader for DiskReader {
     fn assert_header(&mut self, text: &str) -> Result<(), MIDILoadError> {
-        let reader = &mut self.reader;
-        let chars = text.as_bytes();
-        let mut bytes = vec![0 as u8; chars.len()];
-        let read = reader.read_exact(&mut bytes);
+        let offset = self.get_position()?;
+        let mut bytes = vec![0 as u8; text.len()];
+        e(self.reader.read_exact(&mut bytes), offset)?;
 
-        if read.is_err() {
-            return Err(MIDILoadError::CorruptChunks);
-        }
-
-        for i in 0..chars.len() {
-            if chars[i] != bytes[i] {
-                return Err(MIDILoadError::CorruptChunks);
-            }
-        }
-        return Ok(());
+        check_header(text, &bytes, offset)
     }
 
     fn read_value(&mut self, bytes: i32) -> Result<u32, MIDILoadError> {
+        let offset = self.get_position()?;
         let reader = &mut self.reader;
 
         let mut b = vec![0 as u8; bytes as usize];
-        let read = e(reader.read_exact(&mut b));
+        let read = e(reader.read_exact(&mut b), offset);
 
         match read {
             Err(e) => Err(e),
@@ -133,38 +194,82 @@ impl MIDIReader for DiskReader {
     }
 
     fn get_position(&mut self) -> Result<u64, MIDILoadError> {
-        e(self.reader.stream_position())
+        Ok(self.reader.stream_position()?)
+    }
+
+    fn seek(&mut self, pos: u64) -> Result<(), MIDILoadError> {
+        self.reader.seek(SeekFrom::Start(pos.min(self.length)))?;
+        Ok(())
     }
 
-    fn is_end(&mut self) -> Result<bool, MIDILoadError> {
-        Ok(self.get_position()? == self.length)
+    fn length(&self) -> u64 {
+        self.length
     }
 
-    fn skip(&mut self, bytes: u64) -> Result<u64, MIDILoadError> {
-        let pos = self.get_position()?;
-        let mut to = pos as u64 + bytes;
-        if to > self.length as u64 {
-            to = self.length as u64;
+    fn find(&mut self, pattern: &[u8], from: u64) -> Result<Option<u64>, MIDILoadError> {
+        let mut buffer = vec![0; DISK_TRACK_BUFFER_SIZE as usize];
+        let mut start = from;
+
+        while start + pattern.len() as u64 <= self.length {
+            let size = (self.length - start).min(buffer.len() as u64) as usize;
+            self.reader.seek(SeekFrom::Start(start))?;
+            e(self.reader.read_exact(&mut buffer[..size]), start)?;
+
+            let found = buffer[..size]
+                .windows(pattern.len())
+                .position(|w| w == pattern);
+            if let Some(p) = found {
+                return Ok(Some(start + p as u64));
+            }
+
+            // Overlap the windows so matches across buffer edges aren't missed
+            start += (size - pattern.len() + 1) as u64;
         }
-        e(self.reader.seek(SeekFrom::Start(to)))
+
+        Ok(None)
     }
 
-    fn open_reader(&self, start: u64, len: u64, ram_cache: bool) -> Box<dyn TrackReader> {
-        todo!()
+    fn open_reader(
+        &self,
+        start: u64,
+        len: u64,
+        ram_cache: bool,
+    ) -> Result<Box<dyn TrackReader>, MIDILoadError> {
+        check_chunk_bounds(start, len, self.length)?;
+
+        if ram_cache {
+            let mut bytes = vec![0; len as usize];
+            {
+                let mut file = self.track_file.lock().unwrap();
+                e(file.seek(SeekFrom::Start(start)), start)?;
+                e(file.read_exact(&mut bytes), start)?;
+            }
+            Ok(Box::new(FullRamTrackReader {
+                offset: start,
+                pos: 0,
+                end: bytes.len(),
+                bytes: Arc::new(bytes),
+            }))
+        } else {
+            Ok(Box::new(DiskTrackReader {
+                file: self.track_file.clone(),
+                buffer: Vec::new(),
+                buffer_pos: 0,
+                pos: start,
+                end: start + len,
+            }))
+        }
     }
 }
 
 impl MIDIReader for RAMReader {
     fn assert_header(&mut self, text: &str) -> Result<(), MIDILoadError> {
-        let chars = text.as_bytes();
-
-        for i in 0..chars.len() {
-            let read = self.read_byte()?;
-            if chars[i] != read {
-                return Err(MIDILoadError::CorruptChunks);
-            }
+        let offset = self.pos as u64;
+        let mut bytes = Vec::with_capacity(text.len());
+        for _ in 0..text.len() {
+            bytes.push(self.read_byte()?);
         }
-        return Ok(());
+        check_header(text, &bytes, offset)
     }
 
     fn read_value(&mut self, bytes: i32) -> Result<u32, MIDILoadError> {
@@ -179,40 +284,132 @@ impl MIDIReader for RAMReader {
         Ok(self.pos as u64)
     }
 
-    fn is_end(&mut self) -> Result<bool, MIDILoadError> {
-        Ok(self.pos == self.bytes.len())
+    fn seek(&mut self, pos: u64) -> Result<(), MIDILoadError> {
+        self.pos = pos.min(self.bytes.len() as u64) as usize;
+        Ok(())
+    }
+
+    fn length(&self) -> u64 {
+        self.bytes.len() as u64
     }
 
-    fn skip(&mut self, bytes: u64) -> Result<u64, MIDILoadError> {
-        let pos = self.get_position()?;
-        let mut to = pos as u64 + bytes;
-        let len = self.bytes.len();
-        if to > len as u64 {
-            to = len as u64;
+    fn find(&mut self, pattern: &[u8], from: u64) -> Result<Option<u64>, MIDILoadError> {
+        if from as usize >= self.bytes.len() {
+            return Ok(None);
         }
-        self.pos = to as usize;
-        Ok(to)
+
+        let found = self.bytes[from as usize..]
+            .windows(pattern.len())
+            .position(|w| w == pattern);
+        Ok(found.map(|p| from + p as u64))
     }
 
-    fn open_reader<'a>(&self, start: u64, len: u64, _ram_cache: bool) -> Box<dyn TrackReader> {
-        Box::new(FullRamTrackReader {
+    fn open_reader(
+        &self,
+        start: u64,
+        len: u64,
+        _ram_cache: bool,
+    ) -> Result<Box<dyn TrackReader>, MIDILoadError> {
+        check_chunk_bounds(start, len, self.bytes.len() as u64)?;
+
+        Ok(Box::new(FullRamTrackReader {
+            offset: 0,
             pos: start as usize,
             end: (start + len) as usize,
             bytes: self.bytes.clone(),
-        })
+        }))
     }
 }
 
-pub trait TrackReader {
+impl MIDIReader for MmapReader {
+    fn assert_header(&mut self, text: &str) -> Result<(), MIDILoadError> {
+        let offset = self.pos as u64;
+        let mut bytes = Vec::with_capacity(text.len());
+        for _ in 0..text.len() {
+            bytes.push(self.read_byte()?);
+        }
+        check_header(text, &bytes, offset)
+    }
+
+    fn read_value(&mut self, bytes: i32) -> Result<u32, MIDILoadError> {
+        let mut num: u32 = 0;
+        for _ in 0..bytes {
+            num = (num << 8) + self.read_byte()? as u32;
+        }
+        Ok(num)
+    }
+
+    fn get_position(&mut self) -> Result<u64, MIDILoadError> {
+        Ok(self.pos as u64)
+    }
+
+    fn seek(&mut self, pos: u64) -> Result<(), MIDILoadError> {
+        self.pos = pos.min(self.mmap.len() as u64) as usize;
+        Ok(())
+    }
+
+    fn length(&self) -> u64 {
+        self.mmap.len() as u64
+    }
+
+    fn find(&mut self, pattern: &[u8], from: u64) -> Result<Option<u64>, MIDILoadError> {
+        if from as usize >= self.mmap.len() {
+            return Ok(None);
+        }
+
+        let found = self.mmap[from as usize..]
+            .windows(pattern.len())
+            .position(|w| w == pattern);
+        Ok(found.map(|p| from + p as u64))
+    }
+
+    fn open_reader(
+        &self,
+        start: u64,
+        len: u64,
+        _ram_cache: bool,
+    ) -> Result<Box<dyn TrackReader>, MIDILoadError> {
+        check_chunk_bounds(start, len, self.mmap.len() as u64)?;
+
+        Ok(Box::new(MmapTrackReader {
+            pos: start as usize,
+            end: (start + len) as usize,
+            mmap: self.mmap.clone(),
+        }))
+    }
+}
+
+pub trait TrackReader: Send {
     fn read(&mut self) -> Result<u8, MIDILoadError>;
+
+    /// File offset of the next byte to be read
+    fn position(&self) -> u64;
+
+    /// Number of bytes left in the track
+    fn remaining(&self) -> u64;
 }
 
 pub struct FullRamTrackReader {
     bytes: Arc<Vec<u8>>,
+    /// File offset of `bytes[0]`
+    offset: u64,
     pos: usize,
     end: usize,
 }
 
+impl FullRamTrackReader {
+    /// Reads a track's bytes on their own, as if the track started the file
+    #[cfg(test)]
+    pub fn new(bytes: Vec<u8>) -> Self {
+        FullRamTrackReader {
+            offset: 0,
+            pos: 0,
+            end: bytes.len(),
+            bytes: Arc::new(bytes),
+        }
+    }
+}
+
 impl TrackReader for FullRamTrackReader {
     fn read(&mut self) -> Result<u8, MIDILoadError> {
         if self.pos == self.end {
@@ -222,4 +419,179 @@ impl TrackReader for FullRamTrackReader {
         self.pos += 1;
         Ok(b)
     }
+
+    fn position(&self) -> u64 {
+        self.offset + self.pos as u64
+    }
+
+    fn remaining(&self) -> u64 {
+        (self.end - self.pos) as u64
+    }
+}
+
+/// Reads a track straight out of the shared memory map, without copying
+pub struct MmapTrackReader {
+    mmap: Arc<Mmap>,
+    pos: usize,
+    end: usize,
+}
+
+impl TrackReader for MmapTrackReader {
+    fn read(&mut self) -> Result<u8, MIDILoadError> {
+        if self.pos == self.end {
+            return Err(MIDILoadError::OutOfBoundsError);
+        }
+        let b = self.mmap[self.pos];
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn position(&self) -> u64 {
+        self.pos as u64
+    }
+
+    fn remaining(&self) -> u64 {
+        (self.end - self.pos) as u64
+    }
+}
+
+/// Streams a track from disk through a small buffer, refilling it from the
+/// shared file handle whenever it runs dry
+pub struct DiskTrackReader {
+    file: Arc<Mutex<File>>,
+    buffer: Vec<u8>,
+    buffer_pos: usize,
+    pos: u64,
+    end: u64,
+}
+
+impl DiskTrackReader {
+    fn fill_buffer(&mut self) -> Result<(), MIDILoadError> {
+        let size = (self.end - self.pos).min(DISK_TRACK_BUFFER_SIZE) as usize;
+        self.buffer.resize(size, 0);
+
+        {
+            let mut file = self.file.lock().unwrap();
+            e(file.seek(SeekFrom::Start(self.pos)), self.pos)?;
+            e(file.read_exact(&mut self.buffer), self.pos)?;
+        }
+
+        self.pos += size as u64;
+        self.buffer_pos = 0;
+        Ok(())
+    }
+}
+
+impl TrackReader for DiskTrackReader {
+    fn read(&mut self) -> Result<u8, MIDILoadError> {
+        if self.buffer_pos == self.buffer.len() {
+            if self.pos == self.end {
+                return Err(MIDILoadError::OutOfBoundsError);
+            }
+            self.fill_buffer()?;
+        }
+        let b = self.buffer[self.buffer_pos];
+        self.buffer_pos += 1;
+        Ok(b)
+    }
+
+    fn position(&self) -> u64 {
+        self.pos - (self.buffer.len() - self.buffer_pos) as u64
+    }
+
+    fn remaining(&self) -> u64 {
+        self.end - self.position()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::MIDILoadError,
+        filter::NoteFilter,
+        midifile::{MIDIFile, MIDILoadOptions, MIDIReaderMode},
+        tempo::Timeline,
+        testing::{parse, smf, tree_values, TempFile, TrackBuilder},
+    };
+
+    #[test]
+    fn disk_tracks_span_buffers() {
+        // Two note tracks of several buffers each, read side by side through
+        // the shared file handle
+        let mut tracks = vec![TrackBuilder::new().tempo(0, 400000).end(0)];
+        for (channel, notes) in [(0, 9000), (1, 7000)].iter() {
+            let mut track = TrackBuilder::new();
+            for i in 0..*notes {
+                let key = (i % 100) as u8;
+                track = track
+                    .note_on(1 + i % 3, *channel, key, 64)
+                    .note_off(2, *channel, key);
+            }
+            tracks.push(track.end(0));
+        }
+        assert!(tracks[1].len() as u64 > 2 * super::DISK_TRACK_BUFFER_SIZE);
+        let file = TempFile::new(&smf(480, &tracks));
+
+        let parse = |mode| {
+            let timeline = Timeline::Seconds { tps: 1000 };
+            let options = MIDILoadOptions::default();
+            parse(&file, mode, options, timeline, &NoteFilter::default()).unwrap()
+        };
+        let (ram, ram_trees) = parse(MIDIReaderMode::Ram);
+        let (disk, disk_trees) = parse(MIDIReaderMode::Disk);
+        assert_eq!(disk.stats().as_ref().unwrap().note_count, 16000);
+        assert_eq!(tree_values(&disk_trees), tree_values(&ram_trees));
+
+        for track in 0..3 {
+            let events = |midi: &MIDIFile| {
+                midi.track_events(track)
+                    .unwrap()
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap()
+            };
+            assert_eq!(events(&disk), events(&ram));
+        }
+    }
+
+    const MODES: [MIDIReaderMode; 3] = [
+        MIDIReaderMode::Ram,
+        MIDIReaderMode::Disk,
+        MIDIReaderMode::Mmap,
+    ];
+
+    #[test]
+    fn empty_files() {
+        let file = TempFile::new(&[]);
+        for &mode in &MODES {
+            let result = MIDIFile::new(file.path(), mode, None);
+            assert!(
+                matches!(result, Err(MIDILoadError::UnexpectedEnd { offset: 0 })),
+                "{:?}",
+                mode
+            );
+        }
+    }
+
+    #[test]
+    fn readers_agree() {
+        let notes = TrackBuilder::new()
+            .note_on(0, 0, 60, 100)
+            .note_on(10, 1, 62, 80)
+            .note_off(30, 0, 60)
+            .note_off(5, 1, 62)
+            .end(0);
+        let file = TempFile::new(&smf(96, &[notes]));
+
+        let trees = MODES
+            .iter()
+            .map(|&mode| {
+                let timeline = Timeline::Ticks;
+                let options = MIDILoadOptions::default();
+                let filter = NoteFilter::default();
+                tree_values(&parse(&file, mode, options, timeline, &filter).unwrap().1)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(trees[1], trees[0]);
+        assert_eq!(trees[2], trees[0]);
+    }
 }
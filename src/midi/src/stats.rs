@@ -0,0 +1,213 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use crate::{
+    events::{MidiEvent, TrackEvent},
+    miditrack::OverlapPolicy,
+    tempo::TempoMap,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackStats {
+    pub note_count: u64,
+    pub event_count: u64,
+    /// Most notes sounding at once within the track
+    pub polyphony_peak: u32,
+    /// Tick of the track's last event
+    pub length_ticks: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MidiStats {
+    pub tracks: Vec<TrackStats>,
+    pub note_count: u64,
+    pub event_count: u64,
+    /// Most notes sounding at once across all tracks. Measuring it means
+    /// reading every track side by side, which `MIDIFile::scan_stats` and
+    /// `MIDIFile::parse_all_tracks` do in a pass of their own.
+    pub polyphony_peak: Option<u32>,
+    pub length_ticks: u64,
+    pub length_seconds: f64,
+    /// Number of nodes in the serialized trees, only known after parsing
+    pub tree_nodes: Option<u64>,
+}
+
+/// Collects a track's statistics from its events. Polyphony is measured at the
+/// end of each tick, so notes that start and end on the same tick don't count.
+pub(crate) struct TrackStatsCounter {
+    stats: TrackStats,
+    overlap_policy: OverlapPolicy,
+    active: Vec<u32>,
+    sounding: u32,
+    tick: u64,
+    tick_start_sounding: u32,
+}
+
+impl TrackStatsCounter {
+    pub fn new(overlap_policy: OverlapPolicy) -> Self {
+        TrackStatsCounter {
+            stats: TrackStats::default(),
+            overlap_policy,
+            active: vec![0; 256 * 16],
+            sounding: 0,
+            tick: 0,
+            tick_start_sounding: 0,
+        }
+    }
+
+    /// Ends the current tick, returning how the number of sounding notes
+    /// changed over it
+    fn end_tick(&mut self) -> Option<(u64, i32)> {
+        if self.sounding == self.tick_start_sounding {
+            return None;
+        }
+        let change = self.sounding as i32 - self.tick_start_sounding as i32;
+        self.stats.polyphony_peak = self.stats.polyphony_peak.max(self.sounding);
+        self.tick_start_sounding = self.sounding;
+        Some((self.tick, change))
+    }
+
+    /// Counts an event. If it starts a new tick, returns the `(tick, change)`
+    /// in sounding notes over the previous one, if there was any.
+    pub fn record(&mut self, event: &TrackEvent) -> Option<(u64, i32)> {
+        let mut change = None;
+        if event.tick != self.tick {
+            change = self.end_tick();
+            self.tick = event.tick;
+        }
+
+        self.stats.event_count += 1;
+        self.stats.length_ticks = event.tick;
+
+        let merge = self.overlap_policy == OverlapPolicy::Merge;
+        match event.event {
+            MidiEvent::NoteOn {
+                channel,
+                key,
+                velocity,
+            } if velocity > 0 => {
+                let active = &mut self.active[key as usize * 16 + channel as usize];
+                if !merge || *active == 0 {
+                    self.stats.note_count += 1;
+                    self.sounding += 1;
+                }
+                *active += 1;
+            }
+            MidiEvent::NoteOn { channel, key, .. } | MidiEvent::NoteOff { channel, key, .. } => {
+                let active = &mut self.active[key as usize * 16 + channel as usize];
+                if *active > 0 {
+                    *active -= 1;
+                    if !merge || *active == 0 {
+                        self.sounding -= 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        change
+    }
+
+    /// Ends the notes still sounding at the track's last tick, returning the
+    /// last tick's change in sounding notes like `record`
+    pub fn finish(&mut self) -> Option<(u64, i32)> {
+        let change = self.end_tick().map_or(0, |(_, change)| change);
+        let change = change - self.sounding as i32;
+        self.sounding = 0;
+        self.tick_start_sounding = 0;
+        match change {
+            0 => None,
+            change => Some((self.tick, change)),
+        }
+    }
+
+    pub fn into_stats(self) -> TrackStats {
+        self.stats
+    }
+}
+
+/// Finds the most notes sounding at once across tracks, given each track's
+/// `(tick, change)` pairs in tick order as `TrackStatsCounter` produces them.
+/// The tracks are merged tick by tick, so only one change per track is held
+/// at a time.
+pub(crate) fn merged_polyphony_peak<I, E>(mut tracks: Vec<I>) -> Result<u32, E>
+where
+    I: Iterator<Item = Result<(u64, i32), E>>,
+{
+    let mut next = BinaryHeap::new();
+    for (i, track) in tracks.iter_mut().enumerate() {
+        if let Some(change) = track.next() {
+            let (tick, change) = change?;
+            next.push(Reverse((tick, i, change)));
+        }
+    }
+
+    let mut sounding = 0i64;
+    let mut peak = 0i64;
+    while let Some(Reverse((tick, i, change))) = next.pop() {
+        sounding += change as i64;
+        if let Some(change) = tracks[i].next() {
+            let (tick, change) = change?;
+            next.push(Reverse((tick, i, change)));
+        }
+
+        // Only the count at the end of a tick counts
+        if !matches!(next.peek(), Some(Reverse((next, _, _))) if *next == tick) {
+            peak = peak.max(sounding);
+        }
+    }
+
+    Ok(peak as u32)
+}
+
+impl MidiStats {
+    /// Combines the statistics of each track, given in track order. The
+    /// polyphony peak across tracks is left unmeasured.
+    pub(crate) fn merge(tracks: Vec<TrackStats>, tempo_map: &TempoMap) -> MidiStats {
+        let mut stats = MidiStats::default();
+
+        for track in tracks {
+            stats.note_count += track.note_count;
+            stats.event_count += track.event_count;
+            stats.length_ticks = stats.length_ticks.max(track.length_ticks);
+            stats.tracks.push(track);
+        }
+
+        stats.length_seconds = tempo_map.tick_to_seconds(stats.length_ticks);
+
+        stats
+    }
+
+    /// Replaces the note counts with the number of notes each track produced
+    pub(crate) fn set_note_counts(&mut self, counts: &[u64]) {
+        for (track, &count) in self.tracks.iter_mut().zip(counts) {
+            track.note_count = count;
+        }
+        self.note_count = self.tracks.iter().map(|t| t.note_count).sum();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(changes: &[(u64, i32)]) -> impl Iterator<Item = Result<(u64, i32), ()>> + '_ {
+        changes.iter().map(|&c| Ok(c))
+    }
+
+    #[test]
+    fn polyphony_across_tracks() {
+        // Two notes from 0 to 10, one from 5 to 20 and one from 10 to 15. The
+        // note starting on 10 as two end doesn't raise the peak past 3.
+        let a = [(0, 2), (10, -1), (15, -1)];
+        let b = [(5, 1), (20, -1)];
+        assert_eq!(merged_polyphony_peak(vec![changes(&a), changes(&b)]), Ok(3));
+
+        // Changes on the same tick in different tracks cancel out first
+        let a = [(0, 1), (10, -1)];
+        let b = [(10, 1), (20, -1)];
+        assert_eq!(merged_polyphony_peak(vec![changes(&a), changes(&b)]), Ok(1));
+
+        let none: Vec<std::iter::Empty<_>> = vec![];
+        assert_eq!(merged_polyphony_peak(none), Ok::<_, ()>(0));
+    }
+}
@@ -0,0 +1,259 @@
+/// Tempo assumed until the first tempo event, in microseconds per quarter note
+pub const DEFAULT_TEMPO: u32 = 500000;
+
+/// The header's time division, either musical or absolute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeDivision {
+    /// Ticks per quarter note, scaled by tempo events
+    Ppq(u16),
+    /// SMPTE timecode. `fps` is the raw frame rate from the header, where 29
+    /// means 29.97 drop-frame. Tempo events don't apply.
+    Smpte { fps: u8, ticks_per_frame: u8 },
+}
+
+impl TimeDivision {
+    pub fn from_header(division: u16) -> Self {
+        if division & 0x8000 == 0 {
+            TimeDivision::Ppq(division)
+        } else {
+            TimeDivision::Smpte {
+                // The high byte is the negated frame rate. Widening first keeps
+                // -128 from overflowing; it's rejected by `is_valid` anyway.
+                fps: (-((division >> 8) as i8 as i16)) as u8,
+                ticks_per_frame: division as u8,
+            }
+        }
+    }
+
+    /// Whether the division can be used for timing. A PPQ of 0, 0 ticks per
+    /// frame and SMPTE frame rates other than 24, 25, 29 and 30 can't.
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            TimeDivision::Ppq(ppq) => ppq != 0,
+            TimeDivision::Smpte {
+                fps,
+                ticks_per_frame,
+            } => matches!(fps, 24 | 25 | 29 | 30) && ticks_per_frame != 0,
+        }
+    }
+
+    fn seconds_per_tick(&self, tempo: u32) -> f64 {
+        match *self {
+            TimeDivision::Ppq(ppq) => (tempo as f64 / ppq as f64) / 1000000.0,
+            TimeDivision::Smpte {
+                fps,
+                ticks_per_frame,
+            } => {
+                let fps = match fps {
+                    29 => 30000.0 / 1001.0,
+                    fps => fps as f64,
+                };
+                1.0 / (fps * ticks_per_frame as f64)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TempoSegment {
+    tick: u64,
+    seconds: f64,
+    seconds_per_tick: f64,
+}
+
+/// Maps MIDI ticks to absolute time and back, built from every tempo event in
+/// the file regardless of which track it came from
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    segments: Vec<TempoSegment>,
+}
+
+impl TempoMap {
+    /// Builds the map from `(tick, tempo)` changes. Changes must be sorted by
+    /// tick; when several share a tick, the last one wins. SMPTE divisions run
+    /// at a fixed rate, so their changes are ignored.
+    pub fn new(division: TimeDivision, changes: &[(u64, u32)]) -> Self {
+        let seconds_per_tick = |tempo: u32| division.seconds_per_tick(tempo);
+
+        let mut segments = vec![TempoSegment {
+            tick: 0,
+            seconds: 0.0,
+            seconds_per_tick: seconds_per_tick(DEFAULT_TEMPO),
+        }];
+
+        if let TimeDivision::Smpte { .. } = division {
+            return TempoMap { segments };
+        }
+
+        for &(tick, tempo) in changes {
+            let last = *segments.last().unwrap();
+            debug_assert!(tick >= last.tick);
+
+            let segment = TempoSegment {
+                tick,
+                seconds: last.seconds + (tick - last.tick) as f64 * last.seconds_per_tick,
+                seconds_per_tick: seconds_per_tick(tempo),
+            };
+
+            if tick == last.tick {
+                *segments.last_mut().unwrap() = TempoSegment {
+                    seconds: last.seconds,
+                    ..segment
+                };
+            } else {
+                segments.push(segment);
+            }
+        }
+
+        TempoMap { segments }
+    }
+
+    fn segment_at_tick(&self, tick: u64) -> &TempoSegment {
+        let i = match self.segments.binary_search_by_key(&tick, |s| s.tick) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        &self.segments[i]
+    }
+
+    /// Absolute time of a tick, in seconds
+    pub fn tick_to_seconds(&self, tick: u64) -> f64 {
+        let segment = self.segment_at_tick(tick);
+        segment.seconds + (tick - segment.tick) as f64 * segment.seconds_per_tick
+    }
+
+    /// The map as `(tick, seconds, seconds_per_tick)` segments, for saving it
+    pub(crate) fn to_segments(&self) -> Vec<(u64, f64, f64)> {
+        self.segments
+            .iter()
+            .map(|s| (s.tick, s.seconds, s.seconds_per_tick))
+            .collect()
+    }
+
+    /// Rebuilds a map saved with `to_segments`, or `None` if the segments
+    /// don't start at tick 0 in increasing order
+    pub(crate) fn from_segments(segments: &[(u64, f64, f64)]) -> Option<Self> {
+        if segments.first()?.0 != 0 || segments.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return None;
+        }
+
+        let segments = segments
+            .iter()
+            .map(|&(tick, seconds, seconds_per_tick)| TempoSegment {
+                tick,
+                seconds,
+                seconds_per_tick,
+            })
+            .collect();
+        Some(TempoMap { segments })
+    }
+
+    /// Fractional tick at an absolute time in seconds
+    pub fn seconds_to_tick(&self, seconds: f64) -> f64 {
+        let i = self.segments.partition_point(|s| s.seconds <= seconds);
+        let segment = &self.segments[i.max(1) - 1];
+        segment.tick as f64 + (seconds - segment.seconds) / segment.seconds_per_tick
+    }
+}
+
+/// The unit notes are placed on in the trees
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timeline {
+    /// Absolute time, quantised to `tps` units per second
+    Seconds { tps: u32 },
+    /// The file's own ticks, so notes never collide or drift from rounding.
+    /// The tempo map converts them to absolute time for display.
+    Ticks,
+}
+
+impl Timeline {
+    /// Position of a tick on the timeline
+    pub fn time_at_tick(&self, tempo_map: &TempoMap, tick: u64) -> i64 {
+        match *self {
+            Timeline::Seconds { tps } => (tempo_map.tick_to_seconds(tick) * tps as f64) as i64,
+            Timeline::Ticks => tick.min(i64::MAX as u64) as i64,
+        }
+    }
+
+    /// Position on the timeline at an absolute time in seconds
+    pub fn time_at_seconds(&self, tempo_map: &TempoMap, seconds: f64) -> i64 {
+        match *self {
+            Timeline::Seconds { tps } => (seconds * tps as f64) as i64,
+            Timeline::Ticks => tempo_map.seconds_to_tick(seconds).floor() as i64,
+        }
+    }
+
+    /// Absolute time in seconds of a position on the timeline
+    pub fn seconds_at(&self, tempo_map: &TempoMap, time: i64) -> f64 {
+        match *self {
+            Timeline::Seconds { tps } => time as f64 / tps as f64,
+            Timeline::Ticks => tempo_map.tick_to_seconds(time.max(0) as u64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smpte_divisions() {
+        assert_eq!(
+            TimeDivision::from_header(0xE728),
+            TimeDivision::Smpte {
+                fps: 25,
+                ticks_per_frame: 40
+            }
+        );
+        assert!(TimeDivision::from_header(0xE728).is_valid());
+        assert!(TimeDivision::from_header(0xE328).is_valid());
+
+        // A high byte of 0x80 is -128, which has to neither overflow nor pass
+        assert!(!TimeDivision::from_header(0x8028).is_valid());
+        assert!(!TimeDivision::from_header(0xE600).is_valid());
+        assert!(!TimeDivision::from_header(0xFF28).is_valid());
+        assert!(!TimeDivision::from_header(0).is_valid());
+        assert!(TimeDivision::from_header(480).is_valid());
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn tempo_round_trip() {
+        // 0.5s per beat, then 0.25s from tick 960, then a change to 2s at tick
+        // 1920 that's replaced by 1s on the same tick
+        let changes = [(960, 250000), (1920, 2000000), (1920, 1000000)];
+        let map = TempoMap::new(TimeDivision::Ppq(480), &changes);
+
+        let expected = [
+            (0, 0.0),
+            (480, 0.5),
+            (960, 1.0),
+            (1440, 1.25),
+            (1920, 1.5),
+            (2400, 2.5),
+            (4800, 7.5),
+        ];
+        for &(tick, seconds) in &expected {
+            assert_close(map.tick_to_seconds(tick), seconds);
+            assert_close(map.seconds_to_tick(seconds), tick as f64);
+        }
+        assert_close(map.seconds_to_tick(1.125), 1200.0);
+
+        for tick in (0..6000).step_by(37) {
+            assert_close(map.seconds_to_tick(map.tick_to_seconds(tick)), tick as f64);
+        }
+        assert_eq!(map.to_segments().len(), 3);
+    }
+
+    #[test]
+    fn same_tick_tempo_changes() {
+        // Changes on tick 0 replace the default tempo, the last one winning
+        let map = TempoMap::new(TimeDivision::Ppq(96), &[(0, 1000000), (0, 250000)]);
+        assert_close(map.tick_to_seconds(96), 0.25);
+        assert_close(map.seconds_to_tick(0.25), 96.0);
+        assert_eq!(map.to_segments().len(), 1);
+    }
+}
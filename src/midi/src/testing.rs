@@ -0,0 +1,171 @@
+//! Builders for the MIDI files used by tests
+
+use std::{
+    env, fs,
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    data::{segment_time, IntVector4},
+    errors::MIDILoadError,
+    filter::NoteFilter,
+    midifile::{MIDIFile, MIDILoadOptions, MIDIReaderMode},
+    tempo::Timeline,
+};
+
+static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+/// A file in the temp directory, deleted when dropped
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(bytes: &[u8]) -> Self {
+        let id = NEXT_FILE.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("cake-test-{}-{}", process::id(), id));
+        fs::write(&path, bytes).unwrap();
+        TempFile { path }
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Encodes a variable-length quantity
+pub fn var_len(mut value: u32) -> Vec<u8> {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.insert(0, (value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes
+}
+
+/// Builds a track's events, each after a delta in ticks
+#[derive(Default)]
+pub struct TrackBuilder {
+    bytes: Vec<u8>,
+}
+
+impl TrackBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn event(mut self, delta: u32, event: &[u8]) -> Self {
+        self.bytes.extend(var_len(delta));
+        self.bytes.extend_from_slice(event);
+        self
+    }
+
+    pub fn note_on(self, delta: u32, channel: u8, key: u8, velocity: u8) -> Self {
+        self.event(delta, &[0x90 | channel, key, velocity])
+    }
+
+    pub fn note_off(self, delta: u32, channel: u8, key: u8) -> Self {
+        self.event(delta, &[0x80 | channel, key, 0])
+    }
+
+    pub fn tempo(self, delta: u32, tempo: u32) -> Self {
+        let [_, a, b, c] = tempo.to_be_bytes();
+        self.event(delta, &[0xFF, 0x51, 0x03, a, b, c])
+    }
+
+    pub fn name(self, delta: u32, name: &str) -> Self {
+        let mut event = vec![0xFF, 0x03, name.len() as u8];
+        event.extend_from_slice(name.as_bytes());
+        self.event(delta, &event)
+    }
+
+    /// Ends the track with an end-of-track event
+    pub fn end(self, delta: u32) -> Vec<u8> {
+        self.event(delta, &[0xFF, 0x2F, 0x00]).bytes
+    }
+}
+
+/// A chunk with its big-endian length
+pub fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut bytes = id.to_vec();
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+/// The header chunk of a format 1 file
+pub fn header(track_count: u16, ppq: u16) -> Vec<u8> {
+    let mut data = 1u16.to_be_bytes().to_vec();
+    data.extend_from_slice(&track_count.to_be_bytes());
+    data.extend_from_slice(&ppq.to_be_bytes());
+    chunk(b"MThd", &data)
+}
+
+/// A format 1 file holding `tracks`
+pub fn smf(ppq: u16, tracks: &[Vec<u8>]) -> Vec<u8> {
+    let mut bytes = header(tracks.len() as u16, ppq);
+    for track in tracks {
+        bytes.extend(chunk(b"MTrk", track));
+    }
+    bytes
+}
+
+/// Parses a file, returning it along with its trees
+pub fn parse(
+    file: &TempFile,
+    mode: MIDIReaderMode,
+    options: MIDILoadOptions,
+    timeline: Timeline,
+    filter: &NoteFilter,
+) -> Result<(MIDIFile, Vec<Vec<IntVector4>>), MIDILoadError> {
+    let mut midi = MIDIFile::new_with_options(file.path(), mode, options, None)?;
+    let trees = midi.parse_all_tracks(timeline, filter, None)?;
+    Ok((midi, trees))
+}
+
+/// The values of the trees, which can be compared
+pub fn tree_values(trees: &[Vec<IntVector4>]) -> Vec<Vec<[i32; 4]>> {
+    trees
+        .iter()
+        .map(|block| {
+            block
+                .iter()
+                .map(|v| [v.val1, v.val2, v.val3, v.val4])
+                .collect()
+        })
+        .collect()
+}
+
+/// The start and end of the note the trees show on `key` at `time`
+pub fn note_at(
+    blocks: &[Vec<IntVector4>],
+    segment_length: i64,
+    key: usize,
+    time: i64,
+) -> Option<(i64, i64)> {
+    let (segment, relative) = segment_time(time, segment_length);
+    let block = &blocks[segment as usize];
+    let mut next = block[key].val1;
+    while next > 0 {
+        let node = block[next as usize];
+        next = match relative < node.val1 {
+            true => node.val2,
+            false => node.val3,
+        };
+    }
+    let leaf = block[-next as usize];
+    let base = segment * segment_length;
+    match leaf.val3 {
+        -1 => None,
+        _ => Some((leaf.val1 as i64 + base, leaf.val2 as i64 + base)),
+    }
+}
@@ -18,17 +18,29 @@ layout (binding = 0) uniform UniformBuffer
     float height;
     int start;
     int end;
-    // int _keyCount;
+    int minVelocity;
+    float velocityDim;
+    int segment;
+    int segmentCount;
+    int firstKey;
+    int keyCount;
+    int segmentLength;
 };
 
 // const int start = 0;
 // const int end = 1505340;
 
-const int keyCount = 128;
+// Trees of the segments the view spans, one block after another. Each block
+// starts with a root per key, and its indices are relative to its start.
+layout (binding = 1) readonly buffer Segments
+{
+    ivec4 Trees[];
+};
 
-layout (binding = 1) readonly buffer BinaryTree
+// Where each of the view's segments starts in Trees
+layout (binding = 2) readonly buffer SegmentStarts
 {
-    ivec3 BinTree[];
+    int BlockStart[];
 };
 
 // layout (binding = 2) readonly buffer Colors
@@ -43,22 +55,22 @@ layout (binding = 1) readonly buffer BinaryTree
 
 const float borderWidth = 0.0015;
 
-ivec3 sampleAt(int pos) {
-    return BinTree[pos];
+ivec4 sampleAt(int block, int pos) {
+    return Trees[block + pos];
 }
 
-ivec3 getNoteAt(uint key, int time) {
-    int nextIndex = sampleAt(int(key)).x;
+ivec4 getNoteAt(uint key, int block, int time) {
+    int nextIndex = sampleAt(block, int(key)).x;
 
     int steps = 0;
     while(nextIndex > 0) {
-        ivec3 node = sampleAt(nextIndex);
+        ivec4 node = sampleAt(block, nextIndex);
         if(time < node.x) nextIndex = node.y;
         else nextIndex = node.z;
         steps++;
     }
 
-    ivec3 note = sampleAt(-nextIndex);
+    ivec4 note = sampleAt(block, -nextIndex);
 
     return note;
 }
@@ -70,15 +82,22 @@ bool midi_is_white(int p) {
 
 void main()
 {
-    int time = int(round(position.y * (end - start) + start));
+    int time = start + int(round(position.y * (end - start)));
+
+    int bound = time / segmentLength;
+    time -= bound * segmentLength;
+    int timeSegment = segment + bound;
+    if (bound >= BlockStart.length() || timeSegment < 0 || timeSegment >= segmentCount) {
+        discard;
+    }
 
-    ivec3 note;
+    ivec4 note;
 
-    note = getNoteAt(key, time);
+    note = getNoteAt(key, BlockStart[bound], time);
 
     // fsout_Color = vec4(0, 0, 1, 1) / 10.0 * steps;
 
-    if (note.z == -1) {
+    if (note.z == -1 || note.w < minVelocity) {
         discard;
     }
 
@@ -107,6 +126,7 @@ void main()
     float minDist = min(vdist, hdist);
 
     vec4 col = vec4((note.z & 0xFF) / 255.0, ((note.z >> 8) & 0xFF) / 255.0, ((note.z >> 16) & 0xFF) / 255.0, 1);
+    col.xyz *= 1.0 - velocityDim * (1.0 - note.w / 127.0);
 
     if(minDist < borderWidth) {
         col.xyz *= 0.6;
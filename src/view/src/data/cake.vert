@@ -1,16 +1,34 @@
 #version 450
 
 layout(location = 0) in vec2 Position;
-layout(location = 1) in vec2 Sides;
 layout(location = 2) in uint Key;
 
 layout(location = 1) out vec2 position;
 layout(location = 2) out flat uint key;
 layout(location = 3) out vec2 sides;
 
+layout (binding = 0) uniform UniformBuffer
+{
+    float width;
+    float height;
+    int start;
+    int end;
+    int minVelocity;
+    float velocityDim;
+    int segment;
+    int segmentCount;
+    int firstKey;
+    int keyCount;
+    int segmentLength;
+};
+
 void main() {
-  position = Position;
-  sides = Sides;
+  // Keys outside the visible range end up off screen
+  float left = float(int(Key) - firstKey) / keyCount;
+  float right = float(int(Key) - firstKey + 1) / keyCount;
+
+  position = vec2(mix(left, right, Position.x), Position.y);
+  sides = vec2(left, right);
   key = Key;
-  gl_Position = vec4(Position * 2 - 1, 0, 1);
+  gl_Position = vec4(position * 2 - 1, 0, 1);
 }
\ No newline at end of file
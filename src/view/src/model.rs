@@ -12,7 +12,7 @@ use imgui_wgpu::{Renderer, Texture, TextureConfig};
 use util::fps::Fps;
 use wgpu::Extent3d;
 
-use crate::renderer::MidiRender;
+use crate::renderer::{MidiRender, RenderError};
 
 pub struct Textures {
     pub pause_button: TextureId,
@@ -88,6 +88,8 @@ pub struct CakeRenderer {
     pub tex_size: Extent3d,
     pub renderer: MidiRender,
     pub texture_id: TextureId,
+    /// Why the last frame couldn't be drawn, if it couldn't
+    pub error: Option<RenderError>,
 }
 
 impl CakeRenderer {
@@ -110,6 +112,7 @@ impl CakeRenderer {
             ),
             tex_size,
             texture_id,
+            error: None,
         }
     }
 
@@ -152,12 +155,23 @@ impl CakeRenderer {
 
     pub fn render(&mut self, renderer: &mut Renderer, graphics: &ApplicationGraphics) {
         let tex = self.borrow_texture(renderer);
-        self.renderer.render(
-            tex.view(),
-            graphics.device(),
-            graphics.queue(),
-            &self.last_size,
-        );
+        let error = self
+            .renderer
+            .render(
+                tex.view(),
+                graphics.device(),
+                graphics.queue(),
+                &self.last_size,
+            )
+            .err();
+
+        // Report each problem once rather than on every frame
+        if let Some(e) = &error {
+            if self.error.as_ref() != Some(e) {
+                println!("Couldn't draw the MIDI: {}", e);
+            }
+        }
+        self.error = error;
     }
 }
 
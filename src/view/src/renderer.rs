@@ -1,4 +1,14 @@
+use std::{collections::HashMap, error::Error, fmt, mem, ops::Range};
+
 use bytemuck::{Pod, Zeroable};
+use midi::{
+    cache::{MidiCache, SourceStamp},
+    data::{segment_time, IntVector4, KeyRange, MAX_SEGMENT_LENGTH},
+    filter::NoteFilter,
+    midifile::{MIDIFile, MIDILoadOptions, MIDIReaderMode},
+    progress::{LoadPhase, LoadProgress, ProgressSink},
+    tempo::{TempoMap, Timeline},
+};
 use wgpu::util::DeviceExt;
 
 #[repr(C)]
@@ -8,6 +18,14 @@ struct RenderUniform {
     height: f32,
     start: i32,
     end: i32,
+    min_velocity: i32,
+    velocity_dim: f32,
+    segment: i32,
+    segment_count: i32,
+    first_key: i32,
+    key_count: i32,
+    segment_length: i32,
+    _padding: [i32; 1],
 }
 
 impl RenderUniform {
@@ -17,6 +35,14 @@ impl RenderUniform {
             start: 0,
             width: 0.0,
             height: 0.0,
+            min_velocity: 0,
+            velocity_dim: 0.0,
+            segment: 0,
+            segment_count: 0,
+            first_key: 0,
+            key_count: 0,
+            segment_length: 0,
+            _padding: [0; 1],
         }
     }
 }
@@ -25,30 +51,28 @@ impl RenderUniform {
 #[derive(Clone, Copy, Pod, Zeroable)]
 struct Vertex {
     pos: [f32; 2],
-    tex_coord: [f32; 2],
     key: i32,
 }
 
-fn vertex(pos: [f32; 2], tc: [f32; 2], key: i32) -> Vertex {
+fn vertex(pos: [f32; 2], key: i32) -> Vertex {
     Vertex {
         pos: [pos[0], pos[1]],
-        tex_coord: [tc[0], tc[1]],
         key,
     }
 }
 
+/// Creates a column for each of the 256 keys. Positions are within the key's
+/// column, which the vertex shader places according to the visible key range.
 fn create_vertices() -> (Vec<Vertex>, Vec<u16>) {
     let mut vertex_data = Vec::new();
     let mut index_data = Vec::new();
 
     for i in 0..256 {
-        let x1 = i as f32 / 128.0;
-        let x2 = (i + 1) as f32 / 128.0;
         vertex_data.append(&mut vec![
-            vertex([x1, 0.0], [x1, x2], i),
-            vertex([x2, 0.0], [x1, x2], i),
-            vertex([x2, 1.0], [x1, x2], i),
-            vertex([x1, 1.0], [x1, x2], i),
+            vertex([0.0, 0.0], i),
+            vertex([1.0, 0.0], i),
+            vertex([1.0, 1.0], i),
+            vertex([0.0, 1.0], i),
         ]);
         index_data.append(&mut vec![
             (i * 4 + 0) as u16,
@@ -63,19 +87,121 @@ fn create_vertices() -> (Vec<Vertex>, Vec<u16>) {
     (vertex_data, index_data)
 }
 
+/// Why the view couldn't be drawn
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenderError {
+    /// The segments the view spans take more bytes than the GPU can bind at
+    /// once. A shorter view, or a parse with shorter segments, fits.
+    ViewTooLarge { bytes: u64, limit: u64 },
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::ViewTooLarge { bytes, limit } => write!(
+                f,
+                "the view spans {} bytes of notes, more than the {} the GPU can bind",
+                bytes, limit
+            ),
+        }
+    }
+}
+
+impl Error for RenderError {}
+
+/// The segments `segment_length` long that a view from `start` to `end` spans,
+/// and the time the view starts at relative to the first of them. Views are
+/// cut to `MAX_SEGMENT_LENGTH`.
+fn view_segments(start: i64, end: i64, segment_length: i64) -> (Range<i64>, i32) {
+    let length = (end - start).clamp(0, MAX_SEGMENT_LENGTH);
+    let (first, relative) = segment_time(start, segment_length);
+    let (last, _) = segment_time(start + length, segment_length);
+    (first..last + 1, relative)
+}
+
+/// Lays out the segments of `bound` one after another, as they're bound for
+/// the shader. Returns where each one starts, in entries, along with the bytes
+/// they take. Segments outside the song are never read, so they take no room
+/// and start anywhere. Fails if the segments or their starts take more bytes
+/// than `limit`.
+fn bound_layout(
+    segments: &[Vec<IntVector4>],
+    bound: Range<i64>,
+    limit: u64,
+) -> Result<(Vec<i32>, u64), RenderError> {
+    let start_bytes = (bound.end - bound.start) as u64 * mem::size_of::<i32>() as u64;
+    if start_bytes > limit {
+        return Err(RenderError::ViewTooLarge {
+            bytes: start_bytes,
+            limit,
+        });
+    }
+
+    let mut entries = 0;
+    let mut starts = Vec::new();
+    for segment in bound {
+        starts.push(entries as i32);
+        if segment >= 0 {
+            entries += segments.get(segment as usize).map_or(0, |s| s.len() as u64);
+        }
+    }
+
+    let bytes = entries * mem::size_of::<IntVector4>() as u64;
+    if bytes > limit {
+        return Err(RenderError::ViewTooLarge { bytes, limit });
+    }
+    Ok((starts, bytes))
+}
+
+/// Creates the buffer the bound segments are copied into
+fn create_window_buffer(device: &wgpu::Device, size: u64) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Segment Window Buffer"),
+        size,
+        usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
 pub struct MidiRender {
     vertex_buf: wgpu::Buffer,
     index_buf: wgpu::Buffer,
     index_count: usize,
-    bind_group: wgpu::BindGroup,
+    bind_group_layout: wgpu::BindGroupLayout,
+    /// Bind group for the segments the view spans, and which those are
+    bind_group: Option<(Range<i64>, wgpu::BindGroup)>,
     uniform_buf: wgpu::Buffer,
     pipeline: wgpu::RenderPipeline,
+    /// Serialized trees of every time segment, uploaded as the view nears them
+    segments: Vec<Vec<IntVector4>>,
+    segment_length: i64,
+    /// Segments currently uploaded to the GPU
+    resident: HashMap<i64, wgpu::Buffer>,
+    /// The bound segments, copied one after another from `resident`
+    window_buf: wgpu::Buffer,
+    /// Size of `window_buf` in bytes
+    window_capacity: u64,
+    timeline: Timeline,
+    tempo_map: TempoMap,
+
+    /// First time shown, at the bottom of the view
+    pub view_start: i64,
+    /// Last time shown, at the top of the view. The view can't be longer than
+    /// `MAX_SEGMENT_LENGTH`.
+    pub view_end: i64,
+    /// Keys shown across the width of the view
+    pub key_range: KeyRange,
+    /// Notes quieter than this aren't drawn
+    pub min_velocity: u8,
+    /// How much quiet notes are darkened, from 0 (not at all) to 1 (silent notes are black)
+    pub velocity_dim: f32,
+    /// Segments after the visible ones kept uploaded, so scrolling forward
+    /// doesn't wait on uploads
+    pub lookahead: u32,
 }
 
 impl MidiRender {
     pub fn init(format: wgpu::TextureFormat, device: &wgpu::Device) -> Self {
-        use std::mem;
-
         // Create the vertex and index buffers
         let vertex_size = mem::size_of::<Vertex>();
         let (vertex_data, index_data) = create_vertices();
@@ -102,7 +228,7 @@ impl MidiRender {
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
                         has_dynamic_offset: false,
-                        min_binding_size: wgpu::BufferSize::new(16),
+                        min_binding_size: wgpu::BufferSize::new(48),
                     },
                     count: None,
                 },
@@ -116,6 +242,16 @@ impl MidiRender {
                     },
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(4),
+                    },
+                    count: None,
+                },
             ],
         });
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
@@ -124,16 +260,36 @@ impl MidiRender {
             push_constant_ranges: &[],
         });
 
-        let mut midi = midi::midifile::MIDIFile::new(
-            "D:\\Midis\\Clubstep.mid",
-            true,
-            Some(&|read| {
-                println!("{}", read);
-            }),
-        )
-        .unwrap();
+        let progress = |p: LoadProgress| {
+            println!("{:?}: {}/{}", p.phase, p.processed, p.total);
+        };
+
+        // Reuse the trees of an earlier parse if the file hasn't changed since
+        // and they were parsed the same way
+        let path = "D:\\Midis\\Clubstep.mid";
+        let cache_path = format!("{}.cake", path);
+        let timeline = Timeline::Seconds { tps: 16384 };
+        let options = MIDILoadOptions::default();
+        let filter = NoteFilter::default();
+        let cache = match MidiCache::load_for(&cache_path, path) {
+            Ok(cache) if cache.made_with(timeline, &options, &filter) => cache,
+            _ => {
+                let mut midi =
+                    MIDIFile::new_with_options(path, MIDIReaderMode::Ram, options, Some(&progress))
+                        .unwrap();
 
-        let vec = midi.parse_all_tracks(16384).expect("MIDI parse failed");
+                let vec = midi
+                    .parse_all_tracks(timeline, &filter, Some(&progress))
+                    .expect("MIDI parse failed");
+
+                let source = SourceStamp::read(path).expect("Hashing MIDI failed");
+                let cache = MidiCache::new(&midi, source, &filter, vec).unwrap();
+                if let Err(e) = cache.save(&cache_path) {
+                    println!("Couldn't save {}: {}", cache_path, e);
+                }
+                cache
+            }
+        };
 
         let data_total = RenderUniform::default();
         let uniform_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -142,27 +298,9 @@ impl MidiRender {
             contents: bytemuck::cast_slice(&[data_total, data_total, data_total, data_total]),
         });
 
-        let cake_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Uniform Buffer"),
-            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
-            contents: bytemuck::cast_slice(&vec),
-        });
-
-        // Create bind group
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: uniform_buf.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: cake_buf.as_entire_binding(),
-                },
-            ],
-            label: None,
-        });
+        // Grown to fit the segments the view spans once it's first drawn
+        let window_capacity = 4 * mem::size_of::<IntVector4>() as u64;
+        let window_buf = create_window_buffer(device, window_capacity);
 
         // Create the render pipeline
         let vs_module = device.create_shader_module(&wgpu::include_spirv!("data\\cake.vert.spv"));
@@ -183,14 +321,9 @@ impl MidiRender {
                             offset: 0,
                             shader_location: 0,
                         },
-                        wgpu::VertexAttribute {
-                            format: wgpu::VertexFormat::Float32x2,
-                            offset: 2 * 4,
-                            shader_location: 1,
-                        },
                         wgpu::VertexAttribute {
                             format: wgpu::VertexFormat::Uint32,
-                            offset: 4 * 4,
+                            offset: 2 * 4,
                             shader_location: 2,
                         },
                     ],
@@ -218,33 +351,185 @@ impl MidiRender {
         });
 
         // Done
-        MidiRender {
+        let mut render = MidiRender {
             vertex_buf,
             index_buf,
             index_count: index_data.len(),
-            bind_group,
+            bind_group_layout,
+            bind_group: None,
             uniform_buf,
             pipeline,
+            segments: cache.segments,
+            segment_length: cache.segment_length,
+            resident: HashMap::new(),
+            window_buf,
+            window_capacity,
+            timeline,
+            tempo_map: cache.tempo_map,
+            view_start: 0,
+            view_end: 1505340,
+            key_range: cache.key_range,
+            min_velocity: 0,
+            velocity_dim: 0.0,
+            lookahead: 1,
+        };
+
+        // Upload the segments around the start of the song ahead of the first
+        // frame
+        let window = render.window(render.view_segments().0);
+        let upload_total = window.clone().map(|i| render.segment_size(i)).sum::<u64>();
+        let upload_progress = |processed| LoadProgress {
+            phase: LoadPhase::Uploading,
+            processed,
+            total: upload_total,
+        };
+        progress.report(upload_progress(0));
+        render.upload_segments(device, window);
+        progress.report(upload_progress(upload_total));
+
+        render
+    }
+
+    /// Size of a segment's block in bytes
+    fn segment_size(&self, segment: usize) -> u64 {
+        (self.segments[segment].len() * mem::size_of::<IntVector4>()) as u64
+    }
+
+    /// The segments the view spans, and the time the view starts at relative
+    /// to the first of them
+    fn view_segments(&self) -> (Range<i64>, i32) {
+        view_segments(self.view_start, self.view_end, self.segment_length)
+    }
+
+    /// The segments of `segments` that are in the song
+    fn song_segments(&self, segments: Range<i64>) -> Range<usize> {
+        let clamp = |s: i64| s.max(0).min(self.segments.len() as i64) as usize;
+        clamp(segments.start)..clamp(segments.end)
+    }
+
+    /// Segments kept uploaded while the view spans `bound`: those, plus
+    /// `lookahead` more
+    fn window(&self, bound: Range<i64>) -> Range<usize> {
+        self.song_segments(bound.start..bound.end + self.lookahead as i64)
+    }
+
+    /// Uploads the segments of `window` that aren't resident yet, and evicts
+    /// the ones outside it
+    fn upload_segments(&mut self, device: &wgpu::Device, window: Range<usize>) {
+        self.resident
+            .retain(|segment, _| window.contains(&(*segment as usize)));
+        for i in window {
+            let segment = &self.segments[i];
+            self.resident.entry(i as i64).or_insert_with(|| {
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Segment Buffer"),
+                    usage: wgpu::BufferUsage::COPY_SRC,
+                    contents: bytemuck::cast_slice(segment),
+                })
+            });
         }
     }
 
+    /// Streams in the segments around `bound` and binds `bound` for the
+    /// shader, copying them one after another into the window buffer along
+    /// with where each one starts. Fails if they don't fit in one binding.
+    fn stream_segments(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        bound: Range<i64>,
+    ) -> Result<(), RenderError> {
+        let limit = device.limits().max_storage_buffer_binding_size as u64;
+        let (starts, bytes) = bound_layout(&self.segments, bound.clone(), limit)?;
+
+        self.upload_segments(device, self.window(bound.clone()));
+        if matches!(&self.bind_group, Some((segments, _)) if *segments == bound) {
+            return Ok(());
+        }
+
+        if bytes > self.window_capacity {
+            self.window_capacity = bytes.next_power_of_two().min(limit);
+            self.window_buf = create_window_buffer(device, self.window_capacity);
+        }
+
+        for (segment, &start) in bound.clone().zip(&starts) {
+            if let Some(buffer) = self.resident.get(&segment) {
+                let offset = start as u64 * mem::size_of::<IntVector4>() as u64;
+                let size = self.segment_size(segment as usize);
+                encoder.copy_buffer_to_buffer(buffer, 0, &self.window_buf, offset, size);
+            }
+        }
+        let starts_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Segment Starts Buffer"),
+            usage: wgpu::BufferUsage::STORAGE,
+            contents: bytemuck::cast_slice(&starts),
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: self.uniform_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: self.window_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: starts_buf.as_entire_binding(),
+                },
+            ],
+            label: None,
+        });
+        self.bind_group = Some((bound, bind_group));
+        Ok(())
+    }
+
+    /// Shows `length` seconds starting at `start` seconds. On a tick timeline
+    /// the view is linear in ticks, so tempo changes within it stretch the
+    /// notes on either side.
+    pub fn set_view_seconds(&mut self, start: f64, length: f64) {
+        self.view_start = self.timeline.time_at_seconds(&self.tempo_map, start);
+        self.view_end = self.timeline.time_at_seconds(&self.tempo_map, start + length);
+    }
+
+    /// Draws the view, or only clears it if the segments it spans can't be
+    /// bound
     pub fn render(
         &mut self,
         view: &wgpu::TextureView,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         size: &[f32; 2],
-    ) {
+    ) -> Result<(), RenderError> {
+        // The shader works in times relative to the first segment the view
+        // spans, and finds the segment of each time among the bound ones
+        let (bound, start) = self.view_segments();
+        let length = (self.view_end - self.view_start).clamp(0, MAX_SEGMENT_LENGTH);
+        let segment = bound.start;
+
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let streamed = self.stream_segments(device, &mut encoder, bound);
+
         let mx_total = RenderUniform {
-            end: 1505340,
-            start: 0,
+            end: start + length as i32,
+            start,
             width: size[0],
             height: size[1],
+            min_velocity: self.min_velocity as i32,
+            velocity_dim: self.velocity_dim,
+            segment: segment as i32,
+            segment_count: self.segments.len() as i32,
+            first_key: self.key_range.low as i32,
+            key_count: self.key_range.len() as i32,
+            segment_length: self.segment_length as i32,
+            _padding: [0; 1],
         };
         queue.write_buffer(&self.uniform_buf, 0, bytemuck::cast_slice(&[mx_total]));
 
-        let mut encoder =
-            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
         {
             let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: None,
@@ -263,16 +548,77 @@ impl MidiRender {
                 }],
                 depth_stencil_attachment: None,
             });
-            rpass.push_debug_group("Prepare data for draw.");
-            rpass.set_pipeline(&self.pipeline);
-            rpass.set_bind_group(0, &self.bind_group, &[]);
-            rpass.set_index_buffer(self.index_buf.slice(..), wgpu::IndexFormat::Uint16);
-            rpass.set_vertex_buffer(0, self.vertex_buf.slice(..));
-            rpass.pop_debug_group();
-            rpass.insert_debug_marker("Draw!");
-            rpass.draw_indexed(0..self.index_count as u32, 0, 0..1);
+            if streamed.is_ok() {
+                rpass.push_debug_group("Prepare data for draw.");
+                rpass.set_pipeline(&self.pipeline);
+                rpass.set_bind_group(0, &self.bind_group.as_ref().unwrap().1, &[]);
+                rpass.set_index_buffer(self.index_buf.slice(..), wgpu::IndexFormat::Uint16);
+                rpass.set_vertex_buffer(0, self.vertex_buf.slice(..));
+                rpass.pop_debug_group();
+                rpass.insert_debug_marker("Draw!");
+                rpass.draw_indexed(0..self.index_count as u32, 0, 0..1);
+            }
         }
 
         queue.submit(Some(encoder.finish()));
+        streamed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(len: usize) -> Vec<IntVector4> {
+        (0..len).map(|_| IntVector4::default()).collect()
+    }
+
+    #[test]
+    fn segments_in_view() {
+        // Within a segment, and across its boundaries
+        assert_eq!(view_segments(120, 180, 100), (1..2, 20));
+        assert_eq!(view_segments(150, 250, 100), (1..3, 50));
+        assert_eq!(view_segments(50, 350, 100), (0..4, 50));
+
+        // A view ending on a boundary still spans the segment starting there
+        assert_eq!(view_segments(100, 200, 100), (1..3, 0));
+
+        // Before the song, and views that are empty or too long
+        assert_eq!(view_segments(-150, -20, 100), (-2..0, 50));
+        assert_eq!(view_segments(150, 100, 100), (1..2, 50));
+        let longest = MAX_SEGMENT_LENGTH / 100;
+        assert_eq!(view_segments(0, i64::MAX, 100), (0..longest + 1, 0));
+    }
+
+    #[test]
+    fn bound_segments() {
+        let segments = vec![block(300), block(256), block(400)];
+        let entry = mem::size_of::<IntVector4>() as u64;
+
+        let (starts, bytes) = bound_layout(&segments, 0..2, u64::MAX).unwrap();
+        assert_eq!((starts, bytes), (vec![0, 300], 556 * entry));
+
+        // Segments outside the song take no room
+        let (starts, bytes) = bound_layout(&segments, -1..4, u64::MAX).unwrap();
+        assert_eq!((starts, bytes), (vec![0, 0, 300, 556, 956], 956 * entry));
+
+        let limit = 600 * entry;
+        assert!(bound_layout(&segments, 1..3, 656 * entry).is_ok());
+        assert_eq!(
+            bound_layout(&segments, 1..3, limit),
+            Err(RenderError::ViewTooLarge {
+                bytes: 656 * entry,
+                limit
+            })
+        );
+
+        // Even with no notes, every segment bound takes a start
+        assert_eq!(
+            bound_layout(&segments, 10..1010, 1000),
+            Err(RenderError::ViewTooLarge {
+                bytes: 4000,
+                limit: 1000
+            })
+        );
     }
 }